@@ -0,0 +1,450 @@
+use crate::*;
+
+
+/// Summary of a set of elements, fx a sum or a max. A tree made with ['QuadTree::with_aggregate()']
+/// keeps one for every node, so ['QuadTree::aggregate_in()'] can use whole subtrees without visiting their elements.
+/// combine must be associative and commutative, and identity must not change what it is combined with
+pub trait Aggregate<T>: Clone {
+
+    fn identity() -> Self;
+
+    fn combine(&self, other: &Self) -> Self;
+
+    fn from_element(element: &T) -> Self;
+}
+
+
+/// No aggregate, the default. Zero sized aggregates are not maintained by the tree
+impl<T> Aggregate<T> for () {
+
+    fn identity() -> Self {}
+
+    fn combine(&self, _other: &Self) -> Self {}
+
+    fn from_element(_element: &T) -> Self {}
+}
+
+
+// Aggregates changed since a transaction began, so rollback can put them back, see transaction.rs
+pub(crate) struct AggregateJournal<A> {
+    // Length of node_aggregates and node_counts when it began
+    len: usize,
+
+    // Node index, with its aggregate and count before the change
+    changes: Vec::<(usize, A, i32)>,
+}
+
+
+// Part of a query result, see summarize_node
+enum Part<'a, T> {
+    Node(usize),
+    Element(&'a T),
+}
+
+
+// An element is in every leaf it intersects, but it is only counted in the aggregate of the leaf
+// owning its anchor, the top left corner of where it overlaps the root, see ['Rect::owned_quads()'].
+// So every element is in the aggregate of exactly one leaf, and a branch is the combination of its children.
+// Inserting only adds to the nodes above the anchor. Removing has no inverse of combine,
+// so the leaf owning the anchor is combined again from its elements.
+// The number of elements is kept the same way for every tree, but can subtract when removing
+impl<T, A: Aggregate<T>> QuadTree<T, A> {
+
+    /// Combination of all elements intersecting query_r.
+    /// Subtrees inside query_r are used as a whole, only leaves on the edge of query_r visit their elements
+    pub fn aggregate_in(&self, query_r: Rect) -> A {
+
+        let mut res = A::identity();
+
+        if Self::tracks_aggregates() && self.root_rect.intersect(query_r) {
+            self.summarize_node(0, self.root_rect, self.root_rect, query_r, &mut |part| match part {
+                Part::Node(node_index) => res = res.combine(&self.node_aggregates[node_index]),
+                Part::Element(element) => res = res.combine(&A::from_element(element)),
+            });
+        }
+
+        res
+    }
+
+    /// Number of elements intersecting query_r, the same as query_ids would find
+    pub fn count_in(&self, query_r: Rect) -> usize {
+
+        let mut count = 0;
+
+        if self.root_rect.intersect(query_r) {
+            self.summarize_node(0, self.root_rect, self.root_rect, query_r, &mut |part| match part {
+                Part::Node(node_index) => count += self.node_counts[node_index] as usize,
+                Part::Element(_) => count += 1,
+            });
+        }
+
+        count
+    }
+
+    /// Update the aggregates after changing an element through ['get_mut()'] or ['iter_mut()']
+    pub fn refresh_aggregate(&mut self, element_id: ElementId) {
+        if Self::tracks_aggregates() && self.elm_rects.is_live(element_id) {
+            self.fit_aggregates();
+            self.refresh_anchor(self.elm_rects[element_id].rect);
+        }
+    }
+
+
+    // Same elements as query_with reports, see query_node_with. Whole nodes where all elements
+    // anchored below intersect query_r, and the elements from the rest
+    fn summarize_node<'a, F>(&'a self, node_index: i32, node_rect: Rect, owned: Rect, query_r: Rect, f: &mut F)
+    where F: FnMut(Part<'a, T>) {
+
+        // Every element anchored here intersects query_r. query_with reports elements in the owned part of
+        // the leaf with the corner of where element and query overlap. That is the anchor, unless the element
+        // crosses the left or top of owned, which only happens inside query_r when owned is on the edge of query_r
+        if query_r.contains(owned)
+            && (owned.left > query_r.left || owned.left == self.root_rect.left)
+            && (owned.top < query_r.top || owned.top == self.root_rect.top) {
+
+            f(Part::Node(node_index as usize));
+            return;
+        }
+
+        let node = &self.nodes[node_index];
+
+        if node.is_leaf() {
+
+            let mut elm_node_index = node.first_child;
+
+            while elm_node_index != -1 {
+                let elm_node = &self.element_nodes[elm_node_index];
+                let elm = &self.elm_rects[elm_node.elm_id];
+
+                if query_r.intersect(elm.rect) && owned.contains_point(self.anchor(elm.rect.intersection(query_r))) {
+                    f(Part::Element(&self.data[elm.data_id]));
+                }
+
+                elm_node_index = elm_node.next;
+            }
+
+            return;
+        }
+
+        let locations = node_rect.location_quads();
+        let owned_quads = node_rect.owned_quads(owned);
+
+        for i in 0..4 {
+            if locations[i].intersect(query_r) {
+                self.summarize_node(node.first_child + i as i32, locations[i], owned_quads[i], query_r, f);
+            }
+        }
+    }
+
+
+    fn tracks_aggregates() -> bool {
+        std::mem::size_of::<A>() != 0
+    }
+
+
+    // Nodes made since last time get a slot
+    fn fit_aggregates(&mut self) {
+        let len = self.nodes.data_len() as usize;
+
+        if self.node_aggregates.len() < len {
+            self.node_aggregates.resize(len, A::identity());
+            self.node_counts.resize(len, 0);
+        }
+    }
+
+
+    // After element_id was linked into its leaves by node_insert
+    pub(crate) fn aggregate_linked(&mut self, element_id: ElementId) {
+
+        self.fit_aggregates();
+
+        let splits = std::mem::take(&mut self.split_log);
+        let elm = &self.elm_rects[element_id];
+
+        if !elm.rect.intersect(self.root_rect) {
+            return;
+        }
+
+        if splits.is_empty() {
+            let value = A::from_element(&self.data[elm.data_id]);
+            let anchor = self.anchor(elm.rect);
+            self.add_aggregate(anchor, 1, &value);
+            return;
+        }
+
+        // Split leaves keep the same elements, but their new children have no aggregates yet
+        for (node_index, node_rect) in splits {
+            if let Some(owned) = self.find_owned(0, self.root_rect, self.root_rect, node_index, node_rect) {
+                self.recompute_node(node_index, node_rect, owned);
+            }
+        }
+
+        self.refresh_anchor(self.elm_rects[element_id].rect);
+    }
+
+
+    // After an element with rect was unlinked from its leaves
+    pub(crate) fn aggregate_unlinked(&mut self, rect: Rect) {
+
+        self.fit_aggregates();
+
+        if Self::tracks_aggregates() {
+            self.refresh_anchor(rect);
+        }
+        else if rect.intersect(self.root_rect) {
+            self.add_aggregate(self.anchor(rect), -1, &A::identity());
+        }
+    }
+
+
+    // After the 4 nodes at old were moved to new, see maintain
+    pub(crate) fn aggregate_nodes_moved(&mut self, old: i32, new: i32) {
+        self.fit_aggregates();
+
+        for i in 0..4 {
+            self.node_aggregates.swap((old + i) as usize, (new + i) as usize);
+            self.node_counts.swap((old + i) as usize, (new + i) as usize);
+        }
+    }
+
+
+    // After the rect of element_id changed from old without relinking it, see update
+    pub(crate) fn aggregate_moved(&mut self, element_id: ElementId, old: Rect) {
+
+        self.fit_aggregates();
+
+        let new = self.elm_rects[element_id].rect;
+
+        if Self::tracks_aggregates() {
+            self.refresh_anchor(old);
+            self.refresh_anchor(new);
+            return;
+        }
+
+        if old.intersect(self.root_rect) {
+            self.add_aggregate(self.anchor(old), -1, &A::identity());
+        }
+
+        if new.intersect(self.root_rect) {
+            self.add_aggregate(self.anchor(new), 1, &A::identity());
+        }
+    }
+
+
+    // After unlink_in removed all elements intersecting rect
+    pub(crate) fn aggregate_unlinked_in(&mut self, rect: Rect, removed: &[ElementId]) {
+
+        self.fit_aggregates();
+
+        if !Self::tracks_aggregates() {
+            for &element_id in removed {
+                let anchor = self.anchor(self.elm_rects[element_id].rect);
+                self.add_aggregate(anchor, -1, &A::identity());
+            }
+            return;
+        }
+
+        self.recompute_in(0, self.root_rect, self.root_rect, rect);
+
+        // anchors outside rect
+        for &element_id in removed {
+            let elm_rect = self.elm_rects[element_id].rect;
+
+            if !rect.contains(elm_rect) {
+                self.refresh_anchor(elm_rect);
+            }
+        }
+    }
+
+
+    // Combine every aggregate again, after the tree changed shape or many elements changed
+    pub(crate) fn recompute_aggregates(&mut self) {
+        self.split_log.clear();
+        self.fit_aggregates();
+        self.recompute_node(0, self.root_rect, self.root_rect);
+    }
+
+
+    // count is 1 when adding and -1 when removing, in which case value is the identity
+    fn add_aggregate(&mut self, anchor: Point, count: i32, value: &A) {
+
+        let mut node_index = 0;
+        let mut node_rect = self.root_rect;
+
+        loop {
+            let combined = self.node_aggregates[node_index as usize].combine(value);
+            let added = self.node_counts[node_index as usize] + count;
+            self.set_aggregate(node_index, combined, added);
+
+            if self.nodes[node_index].is_leaf() {
+                return;
+            }
+
+            let quad = node_rect.cell_quad(anchor);
+
+            node_index = self.nodes[node_index].first_child + quad as i32;
+            node_rect = node_rect.location_quads()[quad];
+        }
+    }
+
+
+    // Combine the leaf owning the anchor of rect from its elements, and the nodes above it from their children
+    fn refresh_anchor(&mut self, rect: Rect) {
+        if rect.intersect(self.root_rect) {
+            self.refresh_at(0, self.root_rect, self.root_rect, self.anchor(rect));
+        }
+    }
+
+
+    fn refresh_at(&mut self, node_index: i32, node_rect: Rect, owned: Rect, point: Point) {
+
+        if self.nodes[node_index].is_leaf() {
+            self.recompute_node(node_index, node_rect, owned);
+            return;
+        }
+
+        let quad = node_rect.cell_quad(point);
+
+        self.refresh_at(self.nodes[node_index].first_child + quad as i32,
+                        node_rect.location_quads()[quad],
+                        node_rect.owned_quads(owned)[quad],
+                        point);
+
+        self.combine_children(node_index);
+    }
+
+
+    // Combine all nodes intersecting rect again
+    fn recompute_in(&mut self, node_index: i32, node_rect: Rect, owned: Rect, rect: Rect) {
+
+        if self.nodes[node_index].is_leaf() {
+            self.recompute_node(node_index, node_rect, owned);
+            return;
+        }
+
+        let first_child = self.nodes[node_index].first_child;
+        let locations = node_rect.location_quads();
+        let owned_quads = node_rect.owned_quads(owned);
+
+        for i in 0..4 {
+            if locations[i].intersect(rect) {
+                self.recompute_in(first_child + i as i32, locations[i], owned_quads[i], rect);
+            }
+        }
+
+        self.combine_children(node_index);
+    }
+
+
+    // Combine node_index and everything below it again
+    fn recompute_node(&mut self, node_index: i32, node_rect: Rect, owned: Rect) {
+
+        let first_child = self.nodes[node_index].first_child;
+
+        if self.nodes[node_index].is_leaf() {
+
+            let mut value = A::identity();
+            let mut count = 0;
+            let mut elm_node_index = first_child;
+
+            while elm_node_index != -1 {
+                let elm_node = &self.element_nodes[elm_node_index];
+                let elm = &self.elm_rects[elm_node.elm_id];
+
+                if owned.contains_point(self.anchor(elm.rect)) {
+                    value = value.combine(&A::from_element(&self.data[elm.data_id]));
+                    count += 1;
+                }
+
+                elm_node_index = elm_node.next;
+            }
+
+            self.set_aggregate(node_index, value, count);
+            return;
+        }
+
+        let locations = node_rect.location_quads();
+        let owned_quads = node_rect.owned_quads(owned);
+
+        for i in 0..4 {
+            self.recompute_node(first_child + i as i32, locations[i], owned_quads[i]);
+        }
+
+        self.combine_children(node_index);
+    }
+
+
+    fn combine_children(&mut self, node_index: i32) {
+
+        let first_child = self.nodes[node_index].first_child as usize;
+
+        let mut value = A::identity();
+        let mut count = 0;
+
+        for i in 0..4 {
+            value = value.combine(&self.node_aggregates[first_child + i]);
+            count += self.node_counts[first_child + i];
+        }
+
+        self.set_aggregate(node_index, value, count);
+    }
+
+
+    fn set_aggregate(&mut self, node_index: i32, value: A, count: i32) {
+        let slot = node_index as usize;
+
+        if let Some(journal) = &mut self.aggregate_journal {
+            journal.changes.push((slot, self.node_aggregates[slot].clone(), self.node_counts[slot]));
+        }
+
+        self.node_aggregates[slot] = value;
+        self.node_counts[slot] = count;
+    }
+
+
+    // Record changes to the aggregates until commit_aggregates or rollback_aggregates
+    pub(crate) fn begin_aggregates(&mut self) {
+        self.aggregate_journal = Some(AggregateJournal { len: self.node_aggregates.len(), changes: vec![] });
+    }
+
+    pub(crate) fn commit_aggregates(&mut self) {
+        self.aggregate_journal = None;
+    }
+
+    pub(crate) fn rollback_aggregates(&mut self) {
+
+        let journal = match self.aggregate_journal.take() {
+            Some(journal) => journal,
+            None => return,
+        };
+
+        for (slot, value, count) in journal.changes.into_iter().rev() {
+            self.node_aggregates[slot] = value;
+            self.node_counts[slot] = count;
+        }
+
+        self.node_aggregates.truncate(journal.len);
+        self.node_counts.truncate(journal.len);
+    }
+
+
+    // Owned part of target, found by walking down from node_index
+    fn find_owned(&self, node_index: i32, node_rect: Rect, owned: Rect, target: i32, target_rect: Rect) -> Option<Rect> {
+
+        if node_index == target {
+            return Some(owned);
+        }
+
+        let node = &self.nodes[node_index];
+
+        if node.is_leaf() {
+            return None;
+        }
+
+        let locations = node_rect.location_quads();
+        let owned_quads = node_rect.owned_quads(owned);
+
+        (0..4).filter(|&i| locations[i].contains(target_rect))
+            .find_map(|i| self.find_owned(node.first_child + i as i32, locations[i], owned_quads[i], target, target_rect))
+    }
+}
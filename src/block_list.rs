@@ -0,0 +1,402 @@
+use std::fmt;
+use std::ops::{Index, IndexMut};
+use crate::free_list::Remap;
+
+
+// Nodes of a tree, where children are allocated and freed 4 at a time, so first_child + i is always
+// a child of the same node. Index 0 is the root, blocks start at 1.
+// Freed blocks are reused whole, unlike FreeList where 4 inserts only get consecutive slots
+// if the free slots were erased in the right order
+#[derive(Clone)]
+pub(crate) struct BlockList<T> {
+    items: Vec::<T>,
+
+    // Live flag of each block, block b is items 1 + 4 * b to 4 + 4 * b
+    live: Vec::<bool>,
+
+    // First index of free blocks, last is reused first. trim puts the lowest last
+    free: Vec::<i32>,
+
+    // Changes since begin, None when not recording
+    journal: Option<Vec::<Change<T>>>,
+}
+
+
+// How to undo a change, see rollback
+#[derive(Clone)]
+enum Change<T> {
+    // alloc_block added a block at the end
+    Push,
+    // alloc_block reused the free block at first, and these were the items in it
+    Reuse(i32, Vec::<T>),
+    // free_block freed the block at first
+    Free(i32),
+    // the item was changed through IndexMut, and this was the item before
+    Write(i32, T),
+}
+
+
+impl<T> BlockList<T> {
+
+    pub fn new(root: T) -> Self {
+        BlockList {
+            items: vec![root],
+            live: vec![],
+            free: vec![],
+            journal: None,
+        }
+    }
+
+    // Index of the first of the 4 items
+    pub fn alloc_block(&mut self, block: [T; 4]) -> i32 {
+
+        if let Some(first) = self.free.pop() {
+            self.live[Self::block(first)] = true;
+
+            let recording = self.journal.is_some();
+            let mut old = vec![];
+
+            for (i, item) in IntoIterator::into_iter(block).enumerate() {
+                let replaced = std::mem::replace(&mut self.items[first as usize + i], item);
+
+                if recording {
+                    old.push(replaced);
+                }
+            }
+
+            self.record(Change::Reuse(first, old));
+
+            return first;
+        }
+
+        let first = self.items.len() as i32;
+        self.items.extend(block);
+        self.live.push(true);
+
+        self.record(Change::Push);
+
+        first
+    }
+
+    pub fn free_block(&mut self, first: i32) {
+        assert!(first > 0 && (first - 1) % 4 == 0, "Freeing {} which is not the start of a block", first);
+        assert!(self.live[Self::block(first)], "Freeing free block {}", first);
+
+        self.live[Self::block(first)] = false;
+        self.free.push(first);
+
+        self.record(Change::Free(first));
+    }
+
+    // Release free blocks at the end, and reuse the rest lowest first
+    pub fn trim(&mut self) {
+        assert!(self.journal.is_none(), "Trimming BlockList while recording");
+
+        while self.live.last() == Some(&false) {
+            self.live.pop();
+        }
+
+        let len = 1 + 4 * self.live.len();
+        self.items.truncate(len);
+        self.free.retain(|&first| (first as usize) < len);
+        self.free.sort_unstable_by(|a, b| b.cmp(a));
+    }
+
+    // True if the next free block to reuse is before the block at first
+    pub fn can_move_down(&self, first: i32) -> bool {
+        self.free.last().is_some_and(|&to| to < first)
+    }
+
+    // Move the block at first to the next free block to reuse, if that is lower. Returns where the block is now.
+    // The old block is reused last, so moving the next block does not just fill it again
+    pub fn move_down(&mut self, first: i32) -> i32 {
+        assert!(self.journal.is_none(), "Moving in BlockList while recording");
+        assert!(self.live[Self::block(first)], "Moving free block {}", first);
+
+        if !self.can_move_down(first) {
+            return first;
+        }
+
+        let to = self.free.pop().unwrap();
+
+        for i in 0..4 {
+            self.items.swap(first as usize + i, to as usize + i);
+        }
+
+        self.live[Self::block(to)] = true;
+        self.live[Self::block(first)] = false;
+        self.free.insert(0, first);
+
+        to
+    }
+
+    pub fn data_len(&self) -> i32 {
+        self.items.len() as i32
+    }
+
+    // Number of live items, the root included
+    #[cfg(test)]
+    pub fn elements_count(&self) -> i32 {
+        1 + 4 * (self.live.len() - self.free.len()) as i32
+    }
+
+    pub fn is_live(&self, n: i32) -> bool {
+        n == 0 || (n > 0 && (n as usize) < self.items.len() && self.live[Self::block(n)])
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (i32, &T)> + '_ {
+        self.items.iter().enumerate()
+            .map(|(i, item)| (i as i32, item))
+            .filter(move |&(i, _)| self.is_live(i))
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (i32, &mut T)> + '_ {
+        assert!(self.journal.is_none(), "Changing BlockList through iter_mut while recording");
+
+        let live = &self.live;
+
+        self.items.iter_mut().enumerate()
+            .filter(move |&(i, _)| i == 0 || live[Self::block(i as i32)])
+            .map(|(i, item)| (i as i32, item))
+    }
+
+    // Move live blocks to the front, keeping their order, and release the rest
+    pub fn compact(&mut self) -> Remap {
+        assert!(self.journal.is_none(), "Compacting BlockList while recording");
+
+        let mut new_index = vec![-1; self.items.len()];
+        new_index[0] = 0;
+
+        let mut next = 1;
+
+        for (b, &live) in self.live.iter().enumerate() {
+            if live {
+                for i in 0..4 {
+                    new_index[1 + 4 * b + i] = next + i as i32;
+                }
+                next += 4;
+            }
+        }
+
+        let live = std::mem::take(&mut self.live);
+        let mut index = 0;
+
+        self.items.retain(|_| {
+            let keep = index == 0 || live[(index - 1) / 4];
+            index += 1;
+            keep
+        });
+
+        self.items.shrink_to_fit();
+        self.live = vec![true; (self.items.len() - 1) / 4];
+        self.free = vec![];
+
+        Remap::new(new_index)
+    }
+
+    fn block(n: i32) -> usize {
+        (n as usize - 1) / 4
+    }
+
+    fn record(&mut self, change: Change<T>) {
+        if let Some(journal) = &mut self.journal {
+            journal.push(change);
+        }
+    }
+}
+
+
+impl<T: Clone> BlockList<T> {
+
+    // Start recording allocs, frees and changes through IndexMut, so rollback can undo them
+    pub fn begin(&mut self) {
+        self.journal = Some(vec![]);
+    }
+
+    // Keep the changes since begin and stop recording
+    pub fn commit(&mut self) {
+        self.journal = None;
+    }
+
+    // Undo all changes since begin and stop recording. Free blocks are reused in the same order as before
+    pub fn rollback(&mut self) {
+
+        let journal = self.journal.take().unwrap_or_default();
+
+        for change in journal.into_iter().rev() {
+            match change {
+                Change::Push => {
+                    self.items.truncate(self.items.len() - 4);
+                    self.live.pop();
+                },
+                Change::Reuse(first, old) => {
+                    for (i, item) in old.into_iter().enumerate() {
+                        self.items[first as usize + i] = item;
+                    }
+
+                    self.live[Self::block(first)] = false;
+                    self.free.push(first);
+                },
+                Change::Free(first) => {
+                    self.live[Self::block(first)] = true;
+                    self.free.pop();
+                },
+                Change::Write(n, item) => {
+                    self.items[n as usize] = item;
+                },
+            }
+        }
+    }
+}
+
+
+impl<T> Index<i32> for BlockList<T> {
+    type Output = T;
+
+    fn index(&self, i: i32) -> &T {
+        &self.items[i as usize]
+    }
+}
+
+
+impl<T: Clone> IndexMut<i32> for BlockList<T> {
+    fn index_mut(&mut self, i: i32) -> &mut T {
+        if let Some(journal) = &mut self.journal {
+            journal.push(Change::Write(i, self.items[i as usize].clone()));
+        }
+
+        &mut self.items[i as usize]
+    }
+}
+
+
+impl<T: fmt::Debug> fmt::Debug for BlockList<T> {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter().map(|(_, item)| item)).finish()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn blocks_stay_together() {
+        let mut bl = BlockList::new(0);
+
+        let a = bl.alloc_block([1, 2, 3, 4]);
+        let b = bl.alloc_block([5, 6, 7, 8]);
+        let c = bl.alloc_block([9, 10, 11, 12]);
+
+        assert_eq!((a, b, c), (1, 5, 9));
+        assert_eq!(bl.elements_count(), 13);
+
+        // freeing in any order reuses whole blocks
+        bl.free_block(a);
+        bl.free_block(c);
+
+        assert!(!bl.is_live(a + 3));
+        assert!(bl.is_live(b));
+        assert!(bl.is_live(0));
+        assert_eq!(bl.elements_count(), 5);
+
+        assert_eq!(bl.alloc_block([13, 14, 15, 16]), c);
+        assert_eq!(bl.alloc_block([17, 18, 19, 20]), a);
+        assert_eq!(bl[a + 2], 19);
+
+        bl.free_block(b);
+
+        let remap = bl.compact();
+
+        assert_eq!(remap.get(0), Some(0));
+        assert_eq!(remap.get(b), None);
+        assert_eq!(remap.get(c), Some(5));
+        assert_eq!(remap.get(c + 3), Some(8));
+        assert_eq!(bl.data_len(), 9);
+        assert_eq!(bl.iter().map(|(_, &v)| v).collect::<Vec<_>>(), vec![0, 17, 18, 19, 20, 13, 14, 15, 16]);
+        assert_eq!(bl.alloc_block([0; 4]), 9);
+    }
+
+
+    #[test]
+    fn trim() {
+        let mut bl = BlockList::new(0);
+
+        let a = bl.alloc_block([1; 4]);
+        let b = bl.alloc_block([2; 4]);
+        let c = bl.alloc_block([3; 4]);
+
+        // only free blocks at the end are released
+        bl.free_block(a);
+        bl.trim();
+        assert_eq!(bl.data_len(), 13);
+
+        bl.free_block(c);
+        bl.free_block(b);
+        bl.trim();
+        assert_eq!(bl.data_len(), 1);
+        assert_eq!(bl.elements_count(), 1);
+
+        assert_eq!(bl.alloc_block([4; 4]), 1);
+        assert_eq!(bl.alloc_block([5; 4]), 5);
+    }
+
+
+    #[test]
+    fn move_down() {
+        let mut bl = BlockList::new(0);
+
+        let blocks: Vec::<i32> = (1..=5).map(|i| bl.alloc_block([i; 4])).collect();
+
+        bl.free_block(blocks[2]);
+        bl.free_block(blocks[0]);
+        bl.free_block(blocks[1]);
+
+        // after trim the lowest free block is used first
+        bl.trim();
+        assert_eq!(bl.move_down(blocks[4]), blocks[0]);
+        assert_eq!(bl.move_down(blocks[3]), blocks[1]);
+        assert_eq!(bl.move_down(blocks[1]), blocks[1]);
+
+        assert_eq!(bl.iter().map(|(_, &v)| v).collect::<Vec<_>>(), vec![0, 5, 5, 5, 5, 4, 4, 4, 4]);
+
+        bl.trim();
+        assert_eq!(bl.data_len(), 9);
+        assert_eq!(bl.elements_count(), 9);
+    }
+
+
+    #[test]
+    fn rollback() {
+        let mut bl = BlockList::new(0);
+
+        let a = bl.alloc_block([1; 4]);
+        let b = bl.alloc_block([2; 4]);
+        let c = bl.alloc_block([3; 4]);
+
+        bl.free_block(c);
+        bl.free_block(a);
+
+        bl.begin();
+
+        assert_eq!(bl.alloc_block([4; 4]), a);
+        bl[0] = 10;
+        bl[b + 1] = 20;
+        bl.free_block(b);
+        assert_eq!(bl.alloc_block([5; 4]), b);
+        assert_eq!(bl.alloc_block([6; 4]), c);
+        assert_eq!(bl.alloc_block([7; 4]), 13);
+
+        bl.rollback();
+
+        assert_eq!(bl.data_len(), 13);
+        assert_eq!(bl.iter().map(|(_, &v)| v).collect::<Vec<_>>(), vec![0, 2, 2, 2, 2]);
+
+        // free blocks in the same order as before begin
+        assert_eq!(bl.alloc_block([8; 4]), a);
+        assert_eq!(bl.alloc_block([9; 4]), c);
+    }
+}
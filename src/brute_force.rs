@@ -0,0 +1,140 @@
+use crate::free_list::{FreeList, Remap};
+use crate::*;
+
+
+/// Index that checks every element on every query. Slow for many elements, but simple enough
+/// to be the reference the other structures are compared with
+pub struct BruteForceIndex<T> {
+    elements: FreeList<(Rect, T)>,
+}
+
+
+impl<T> BruteForceIndex<T> {
+
+    pub fn new() -> Self {
+        BruteForceIndex {
+            elements: FreeList::new(),
+        }
+    }
+}
+
+
+// The same operations as QuadTree, so it can be the reference for them too.
+// There is no structure, so the ones that only change the structure of a QuadTree do nothing
+impl<T> BruteForceIndex<T> {
+
+    /// Like ['QuadTree::insert_dynamic()'], the margin only changes which leaves a QuadTree links the element to
+    pub fn insert_dynamic(&mut self, element: T, rect: Rect, _margin: i32) -> ElementId {
+        self.elements.insert((rect, element))
+    }
+
+    pub fn drain_in(&mut self, rect: Rect) -> impl Iterator<Item = (ElementId, Rect, T)> {
+
+        let mut ids = vec![];
+        self.query_rect(rect, &mut ids);
+
+        let res: Vec::<_> = ids.into_iter()
+            .map(|element_id| {
+                let (rect, element) = self.elements.take(element_id);
+                (element_id, rect, element)
+            })
+            .collect();
+
+        res.into_iter()
+    }
+
+    pub fn remove_in(&mut self, rect: Rect) -> usize {
+        self.drain_in(rect).count()
+    }
+
+    pub fn retain<F>(&mut self, mut f: F) -> usize where F: FnMut(ElementId, &Rect, &mut T) -> bool {
+
+        let mut removed = 0;
+
+        for element_id in 0..self.elements.data_len() {
+            let keep = match self.elements.get_mut(element_id) {
+                Some((rect, element)) => f(element_id, rect, element),
+                None => continue,
+            };
+
+            if !keep {
+                self.elements.erase(element_id);
+                removed += 1;
+            }
+        }
+
+        removed
+    }
+
+    pub fn count_in(&self, rect: Rect) -> usize {
+        self.elements.iter().filter(|(_, (element_rect, _))| rect.intersect(*element_rect)).count()
+    }
+
+    pub fn cleanup(&mut self) {}
+
+    pub fn maintain_steps(&mut self, _n: usize) -> usize {
+        0
+    }
+
+    pub fn shrink_to_fit(&mut self) {}
+
+    /// Like ['QuadTree::compact()'], ids change as given by the returned Remap
+    pub fn compact(&mut self) -> Remap {
+        self.elements.compact()
+    }
+}
+
+
+impl<T> Default for BruteForceIndex<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+impl<T> SpatialIndex<T> for BruteForceIndex<T> {
+
+    fn insert(&mut self, element: T, rect: Rect) -> Result<ElementId, QuadTreeError> {
+        Ok(self.elements.insert((rect, element)))
+    }
+
+    fn remove(&mut self, element_id: ElementId) -> Option<T> {
+        if !self.elements.is_live(element_id) {
+            return None;
+        }
+
+        Some(self.elements.take(element_id).1)
+    }
+
+    fn update(&mut self, element_id: ElementId, rect: Rect) -> Result<(), QuadTreeError> {
+        if !self.elements.is_live(element_id) {
+            return Err(QuadTreeError::InvalidElement(element_id));
+        }
+
+        self.elements[element_id].0 = rect;
+        Ok(())
+    }
+
+    fn query_rect(&self, rect: Rect, output: &mut Vec<ElementId>) {
+        for (element_id, (element_rect, _)) in self.elements.iter() {
+            if rect.intersect(*element_rect) {
+                output.push(element_id);
+            }
+        }
+    }
+
+    fn nearest(&self, point: Point) -> Option<ElementId> {
+        self.elements.iter()
+            .map(|(element_id, (rect, _))| (rect.distance_squared(point), element_id))
+            .min()
+            .map(|(_, element_id)| element_id)
+    }
+
+    fn elements(&self) -> Box<dyn Iterator<Item = (ElementId, Rect, &T)> + '_> {
+        Box::new(self.elements.iter().map(|(element_id, (rect, element))| (element_id, *rect, element)))
+    }
+
+    fn len(&self) -> usize {
+        self.elements.elements_count() as usize
+    }
+}
@@ -1,13 +1,18 @@
 use crate::free_list::FreeList;
 
 //QuadElt is stored once, and is referred to by QuadEltNode
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct ElmRect {
     pub data_id: i32,
-    pub rect: Rect
+    pub rect: Rect,
+
+    // Rect the element is linked into leaves with. Larger than rect for elements from insert_dynamic,
+    // so they can move inside it without relinking
+    pub fat: Rect,
+    pub margin: i32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct ElmRectNode {
     //next node -1 is end of list
     pub next: i32,
@@ -35,7 +40,16 @@ impl ElmRectNode {
     }
 }
 
+// Point stored in a PointQuadTree. Index is shared with the data
 #[derive(Debug)]
+pub(crate) struct ElmPoint {
+    pub point: Point,
+
+    //next point in same leaf -1 is end of list
+    pub next: i32
+}
+
+#[derive(Debug, Clone)]
 pub(crate) struct Node {
     // child are stored continiues
     // child0 (TL) = first_child
@@ -68,7 +82,11 @@ impl Node {
 
 
 
-#[derive(Debug, Clone, Copy)]
+/// Id of an element in a tree, returned by insert
+pub type ElementId = i32;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Point {
     pub x: i32,
     pub y: i32
@@ -91,7 +109,7 @@ impl Point {
         }
     }
 }
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Rect {
     pub left: i32,
     pub top: i32,
@@ -134,48 +152,126 @@ impl Rect {
         ]
     }
 
-    pub fn intersect(&self, other: Rect) -> bool {
-        self.left <= other.right &&
-            self.right >= other.left &&
-            self.top >= other.bottom &&
-            self.bottom <= other.top
+    // Like location_quads, but treats the rect as a grid of integer cells and returns quads
+    // that share no cells. The middle is rounded down, so cells on the middle lines go left and bottom,
+    // otherwise a rect two cells high would have both in the top quads.
+    // Quads can be empty (left > right or bottom > top) when the rect is one cell wide or high
+    pub(crate) fn cell_quads(&self) -> [Rect; 4] {
+
+        let [mut tl, mut tr, bl, mut br] = self.location_quads();
 
+        tl.bottom += 1;
+        tr.bottom += 1;
+        tr.left += 1;
+        br.left += 1;
+
+        [tl, tr, bl, br]
     }
 
-     pub(crate) fn point_quad_locations(node_rect: Rect, point: &Point) -> [bool; 4] {
+    // Index of the cell quad containing point, see cell_quads
+    pub(crate) fn cell_quad(&self, point: Point) -> usize {
 
-        // return bool for TL, TR, BL, BR
+        let node_middle_x = (self.right - self.left) / 2 + self.left;
+        let node_middle_y = (self.top - self.bottom) / 2 + self.bottom;
 
-        let node_middle_x = (node_rect.right - node_rect.left) / 2 + node_rect.left;
-        let node_middle_y = (node_rect.top - node_rect.bottom) / 2 + node_rect.bottom;
+        let right = (point.x > node_middle_x) as usize;
+        let bottom = (point.y <= node_middle_y) as usize;
 
-        //println!("({:?}, {})", node_middle_x, node_middle_y);
-        // check is it inside on X and Y
-        let tl = point.x <= node_middle_x &&
-            point.x >= node_rect.left &&
-            point.y >= node_middle_y &&
-            point.y <= node_rect.top;
+        bottom * 2 + right
+    }
 
+    // Split owned, a part of self, the same way cell_quads splits self.
+    // Used to give every point of the root to exactly one leaf, even though leaf rects overlap
+    pub(crate) fn owned_quads(&self, owned: Rect) -> [Rect; 4] {
+        let mut quads = self.cell_quads();
 
-        let tr = point.x >= node_middle_x &&
-            point.x <= node_rect.right &&
-            point.y >= node_middle_y &&
-            point.y <= node_rect.top;
+        for quad in quads.iter_mut() {
+            *quad = quad.intersection(owned);
+        }
 
+        quads
+    }
 
-        let bl = point.x <= node_middle_x &&
-            point.x >= node_rect.left &&
-            point.y <= node_middle_y &&
-            point.y >= node_rect.bottom;
+    // Twice the width and height, extended towards rect, see QuadTree::grow.
+    // None if self has zero width or height, or the edges, width or height of the result do not fit in i32
+    pub(crate) fn grown_towards(&self, rect: Rect) -> Option<Rect> {
 
+        let width = self.right.checked_sub(self.left)?;
+        let height = self.top.checked_sub(self.bottom)?;
 
-        let br = point.x >= node_middle_x &&
-            point.x <= node_rect.right &&
-            point.y <= node_middle_y &&
-            point.y >= node_rect.bottom;
+        if width <= 0 || height <= 0 {
+            return None;
+        }
 
-        [tl, tr, bl, br]
+        let grow_left = rect.left < self.left;
+        let grow_down = rect.bottom < self.bottom;
+
+        let grown = Rect {
+            left: if grow_left { self.left.checked_sub(width)? } else { self.left },
+            right: if grow_left { self.right } else { self.right.checked_add(width)? },
+            top: if grow_down { self.top } else { self.top.checked_add(height)? },
+            bottom: if grow_down { self.bottom.checked_sub(height)? } else { self.bottom },
+        };
+
+        // location_quads needs the width and height
+        grown.right.checked_sub(grown.left)?;
+        grown.top.checked_sub(grown.bottom)?;
+
+        Some(grown)
+    }
+
+    /// Rect with margin added on all sides
+    pub fn expand(&self, margin: i32) -> Rect {
+        Rect {
+            left: self.left.saturating_sub(margin),
+            right: self.right.saturating_add(margin),
+            top: self.top.saturating_add(margin),
+            bottom: self.bottom.saturating_sub(margin),
+        }
+    }
+
+    /// Part of both rects. Is empty if they do not intersect
+    pub fn intersection(&self, other: Rect) -> Rect {
+        Rect {
+            left: i32::max(self.left, other.left),
+            right: i32::min(self.right, other.right),
+            top: i32::min(self.top, other.top),
+            bottom: i32::max(self.bottom, other.bottom),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.left > self.right || self.bottom > self.top
+    }
+
+    pub fn contains(&self, other: Rect) -> bool {
+        self.left <= other.left &&
+            self.right >= other.right &&
+            self.top >= other.top &&
+            self.bottom <= other.bottom
+    }
+
+    pub fn intersect(&self, other: Rect) -> bool {
+        self.left <= other.right &&
+            self.right >= other.left &&
+            self.top >= other.bottom &&
+            self.bottom <= other.top
+
+    }
+
+    /// Squared distance from point to the closest point of the rect, 0 when inside
+    pub fn distance_squared(&self, point: Point) -> i64 {
+        let dx = i64::max(0, i64::max(self.left as i64 - point.x as i64, point.x as i64 - self.right as i64));
+        let dy = i64::max(0, i64::max(self.bottom as i64 - point.y as i64, point.y as i64 - self.top as i64));
+
+        dx * dx + dy * dy
+    }
 
+    pub fn contains_point(&self, point: Point) -> bool {
+        self.left <= point.x &&
+            self.right >= point.x &&
+            self.top >= point.y &&
+            self.bottom <= point.y
     }
 
      pub(crate) fn element_quad_locations(node_rect: Rect, element_rect: Rect) -> [bool; 4] {
@@ -0,0 +1,198 @@
+use std::io::{self, Write};
+use crate::*;
+
+
+impl<T, A: Aggregate<T>> QuadTree<T, A> {
+
+    /// Number of elements in each of resolution x resolution cells covering the root, from the count of
+    /// the leaves. Rows go from the top of the root to the bottom, so grid[row][column] is ready for an image.
+    /// A leaf spreads its count over the cells it covers by area, so small leaves show up as dense.
+    /// Elements in more than one leaf count in all of them
+    pub fn density_grid(&self, resolution: usize) -> Vec::<Vec::<u32>> {
+
+        if resolution == 0 {
+            return vec![];
+        }
+
+        let mut grid = vec![vec![0.0f64; resolution]; resolution];
+
+        let root = self.root_rect;
+        let cell_width = (root.right - root.left) as f64 / resolution as f64;
+        let cell_height = (root.top - root.bottom) as f64 / resolution as f64;
+
+        for leaf in self.all_leaves() {
+
+            let count = self.nodes[leaf.node_index].count as f64;
+
+            if count == 0.0 {
+                continue;
+            }
+
+            // Leaf rects share their edges, so use them as continuous areas
+            let left = (leaf.rect.left - root.left) as f64;
+            let right = (leaf.rect.right - root.left) as f64;
+            let top = (root.top - leaf.rect.top) as f64;
+            let bottom = (root.top - leaf.rect.bottom) as f64;
+
+            let area = (right - left) * (bottom - top);
+
+            let columns = cell_range(left, right, cell_width, resolution);
+            let rows = cell_range(top, bottom, cell_height, resolution);
+
+            if area <= 0.0 || cell_width <= 0.0 || cell_height <= 0.0 {
+                grid[rows.start][columns.start] += count;
+                continue;
+            }
+
+            for row in rows {
+                let y_overlap = overlap(top, bottom, row as f64 * cell_height, (row + 1) as f64 * cell_height);
+
+                for column in columns.clone() {
+                    let x_overlap = overlap(left, right, column as f64 * cell_width, (column + 1) as f64 * cell_width);
+
+                    grid[row][column] += count * x_overlap * y_overlap / area;
+                }
+            }
+        }
+
+        grid.into_iter()
+            .map(|row| row.into_iter().map(|v| v.round() as u32).collect())
+            .collect()
+    }
+}
+
+
+// Cells from start to end, at least one
+fn cell_range(start: f64, end: f64, cell_size: f64, resolution: usize) -> std::ops::Range<usize> {
+
+    if cell_size <= 0.0 {
+        return 0..1;
+    }
+
+    let first = usize::min((start / cell_size) as usize, resolution - 1);
+    let last = usize::min((end / cell_size).ceil() as usize, resolution);
+
+    first..usize::max(last, first + 1)
+}
+
+
+fn overlap(a_start: f64, a_end: f64, b_start: f64, b_end: f64) -> f64 {
+    f64::max(0.0, f64::min(a_end, b_end) - f64::max(a_start, b_start))
+}
+
+
+/// Write grid as a binary grayscale PGM image, fx to a File. Scaled so the largest value is white
+pub fn write_pgm<W: Write>(grid: &[Vec::<u32>], mut out: W) -> io::Result<()> {
+
+    let (width, height) = grid_size(grid);
+    let max = grid_max(grid);
+
+    write!(out, "P5\n{} {}\n255\n", width, height)?;
+
+    for row in grid {
+        let bytes: Vec::<u8> = row.iter().map(|&v| scale(v, max)).collect();
+        out.write_all(&bytes)?;
+    }
+
+    Ok(())
+}
+
+
+/// Write grid as a binary PPM image, going from black through red and yellow to white
+pub fn write_ppm<W: Write>(grid: &[Vec::<u32>], mut out: W) -> io::Result<()> {
+
+    let (width, height) = grid_size(grid);
+    let max = grid_max(grid);
+
+    write!(out, "P6\n{} {}\n255\n", width, height)?;
+
+    for row in grid {
+        let mut bytes = Vec::with_capacity(row.len() * 3);
+
+        for &v in row {
+            // 0 to 765, one color channel at a time
+            let heat = scale(v, max) as u32 * 3;
+
+            bytes.push(u32::min(heat, 255) as u8);
+            bytes.push(heat.saturating_sub(255).min(255) as u8);
+            bytes.push(heat.saturating_sub(510).min(255) as u8);
+        }
+
+        out.write_all(&bytes)?;
+    }
+
+    Ok(())
+}
+
+
+fn grid_size(grid: &[Vec::<u32>]) -> (usize, usize) {
+    (grid.first().map_or(0, |row| row.len()), grid.len())
+}
+
+
+fn grid_max(grid: &[Vec::<u32>]) -> u32 {
+    grid.iter().flatten().copied().max().unwrap_or(0)
+}
+
+
+fn scale(v: u32, max: u32) -> u8 {
+    if max == 0 {
+        0
+    }
+    else {
+        (v as u64 * 255 / max as u64) as u8
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn density_follows_elements() {
+
+        let rect = Rect::from_points(Point {x: 0, y: 0}, Point { x: 64, y: 64} );
+
+        let mut qt = QuadTree::new(rect);
+
+        qt.set_elements_per_node(9);
+
+        // 8 in the top left quad, then 4 top right and 4 bottom right. The root splits once, at the 9th
+        for i in 0..8 {
+            qt.insert(i, Rect::new(4 + i * 2, 50, 0, 0));
+        }
+        for i in 0..4 {
+            qt.insert(10 + i, Rect::new(40 + i * 4, 50, 0, 0));
+            qt.insert(20 + i, Rect::new(40 + i * 4, 10, 0, 0));
+        }
+
+        // one cell per leaf
+        assert_eq!(qt.density_grid(2), vec![vec![8, 4], vec![0, 4]]);
+
+        // each leaf spread over 2 x 2 cells
+        let grid = qt.density_grid(4);
+        assert_eq!(grid, vec![vec![2, 2, 1, 1],
+                              vec![2, 2, 1, 1],
+                              vec![0, 0, 1, 1],
+                              vec![0, 0, 1, 1]]);
+
+        let mut pgm = vec![];
+        write_pgm(&grid, &mut pgm).unwrap();
+        assert_eq!(&pgm[..11], b"P5\n4 4\n255\n");
+        assert_eq!(&pgm[11..], &[255, 255, 127, 127,
+                                 255, 255, 127, 127,
+                                 0, 0, 127, 127,
+                                 0, 0, 127, 127]);
+
+        let mut ppm = vec![];
+        write_ppm(&grid, &mut ppm).unwrap();
+        assert_eq!(ppm.len(), 11 + 16 * 3);
+        assert_eq!(&ppm[11..14], &[255, 255, 255]);
+        assert_eq!(&ppm[17..20], &[255, 126, 0]);
+        assert_eq!(&ppm[35..38], &[0, 0, 0]);
+
+        assert!(qt.density_grid(0).is_empty());
+    }
+}
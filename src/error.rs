@@ -0,0 +1,42 @@
+use std::fmt;
+use crate::*;
+
+
+/// Errors from the try_ variants of the QuadTree methods
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuadTreeError {
+
+    /// Rect does not touch the root, and the tree is not set to grow
+    OutOfBounds(Rect),
+
+    /// Tree is set to grow, but the root can not grow to contain rect, because it has zero width or height
+    /// or would grow past the range of i32
+    CannotGrow(Rect),
+
+    /// Rect has left > right or bottom > top, fx from Rect::new with a negative size
+    InvalidRect(Rect),
+
+    /// Id is not a live element, it was never inserted or has been removed
+    InvalidElement(ElementId),
+
+    /// Parts for LinearQuadTree::from_sorted_parts do not fit together at this index.
+    /// When their lengths differ it is the length of the shortest
+    InvalidParts(usize),
+}
+
+
+impl fmt::Display for QuadTreeError {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuadTreeError::OutOfBounds(rect) => write!(f, "Rect {:?} is outside the root", rect),
+            QuadTreeError::CannotGrow(rect) => write!(f, "Root cannot grow to contain rect {:?}", rect),
+            QuadTreeError::InvalidRect(rect) => write!(f, "Rect {:?} is inverted", rect),
+            QuadTreeError::InvalidElement(id) => write!(f, "No element with id {}", id),
+            QuadTreeError::InvalidParts(index) => write!(f, "Keys, points and data do not match at index {}", index),
+        }
+    }
+}
+
+
+impl std::error::Error for QuadTreeError {}
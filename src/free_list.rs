@@ -2,16 +2,70 @@ use std::fmt;
 use std::ops::{Index, IndexMut};
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FreeItem<T> {
-    pub item: T,
+    // None when the slot is free
+    pub item: Option<T>,
     next: i32
 }
 
+#[derive(Clone)]
 pub struct FreeList<T> {
     data: Vec::<FreeItem<T>>,
     first_free: i32,
-    elements : i32
+    elements : i32,
+
+    // Inserts and erases since begin, None when not recording
+    journal: Option<Vec::<Change<T>>>,
+
+    // Clones an item before it is changed through IndexMut, set by begin_with_writes
+    save: Option<fn(&T) -> T>,
+}
+
+
+// How to undo an insert or erase, see rollback
+#[derive(Clone)]
+enum Change<T> {
+    // insert added a slot at the end
+    Push,
+    // insert used the first free slot
+    Reuse(i32),
+    // erase freed the slot, and this was the item in it
+    Erase(i32, T),
+    // the item was changed through IndexMut, and this was the item before
+    Write(i32, T),
+}
+
+
+/// Old index to new index of every item, from ['FreeList::compact()']
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Remap {
+    // -1 for slots that were free
+    new_index: Vec::<i32>,
+}
+
+
+impl Remap {
+
+    pub(crate) fn new(new_index: Vec::<i32>) -> Self {
+        Remap { new_index }
+    }
+
+    /// New index of the item that was at old, None if old was a free slot or out of range
+    pub fn get(&self, old: i32) -> Option<i32> {
+        if old < 0 {
+            return None;
+        }
+
+        self.new_index.get(old as usize).copied().filter(|&new| new != -1)
+    }
+
+    /// (old, new) for every item, in index order
+    pub fn iter(&self) -> impl Iterator<Item = (i32, i32)> + '_ {
+        self.new_index.iter().enumerate()
+            .filter(|(_, &new)| new != -1)
+            .map(|(old, &new)| (old as i32, new))
+    }
 }
 
 
@@ -21,7 +75,9 @@ impl<T : > FreeList<T> {
         FreeList {
             data: Vec::new(),
             first_free: -1,
-            elements: 0
+            elements: 0,
+            journal: None,
+            save: None,
         }
     }
 
@@ -30,18 +86,28 @@ impl<T : > FreeList<T> {
         if self.first_free != -1 {
             let index = self.first_free;
             self.first_free = self.data[self.first_free as usize].next;
-            self.data[index as usize].item = item;
+            self.data[index as usize].item = Some(item);
             self.data[index as usize].next = -1;
-            return index;
+
+            if let Some(journal) = &mut self.journal {
+                journal.push(Change::Reuse(index));
+            }
+
+            index
         }
         else {
             let fe = FreeItem {
-                item,
+                item: Some(item),
                 next: -1
             };
 
             self.data.push(fe);
-            return (self.data.len() - 1) as i32;
+
+            if let Some(journal) = &mut self.journal {
+                journal.push(Change::Push);
+            }
+
+            (self.data.len() - 1) as i32
         }
     }
 
@@ -53,10 +119,175 @@ impl<T : > FreeList<T> {
         self.data.len() as i32
     }
 
+    /// Number of items it can hold without allocating, free slots included
+    pub fn capacity(&self) -> usize {
+        self.data.capacity()
+    }
+
+    /// Item at n, None if n is a free slot or out of range
+    pub fn get(&self, n: i32) -> Option<&T> {
+        if n < 0 {
+            return None;
+        }
+
+        self.data.get(n as usize).and_then(|slot| slot.item.as_ref())
+    }
+
+    pub fn get_mut(&mut self, n: i32) -> Option<&mut T> {
+        if n < 0 {
+            return None;
+        }
+
+        self.data.get_mut(n as usize).and_then(|slot| slot.item.as_mut())
+    }
+
     pub fn erase(&mut self, n: i32) {
+        let item = self.free_slot(n);
+
+        if let Some(journal) = &mut self.journal {
+            journal.push(Change::Erase(n, item));
+        }
+    }
+
+    /// Erase item at n and return it. Cannot be used while recording, since rollback would need the item
+    pub fn take(&mut self, n: i32) -> T {
+        assert!(self.journal.is_none(), "Taking from FreeList while recording, use erase");
+
+        self.free_slot(n)
+    }
+
+    /// Start recording inserts and erases, so ['rollback()'] can undo them.
+    /// Erased items are kept until ['commit()']. Changes through IndexMut are not recorded, see ['begin_with_writes()']
+    pub fn begin(&mut self) {
+        self.journal = Some(vec![]);
+        self.save = None;
+    }
+
+    /// Keep the changes since ['begin()'] and stop recording
+    pub fn commit(&mut self) {
+        self.journal = None;
+        self.save = None;
+    }
+
+    /// Undo all recorded changes since ['begin()'] and stop recording.
+    /// Erased items are back at their index, and free slots are reused in the same order as before
+    pub fn rollback(&mut self) {
+
+        let journal = self.journal.take().unwrap_or_default();
+        self.save = None;
+
+        for change in journal.into_iter().rev() {
+            match change {
+                Change::Push => {
+                    self.data.pop();
+                    self.elements -= 1;
+                },
+                Change::Reuse(index) => {
+                    let slot = &mut self.data[index as usize];
+                    slot.item = None;
+                    slot.next = self.first_free;
+                    self.first_free = index;
+                    self.elements -= 1;
+                },
+                Change::Erase(index, item) => {
+                    let slot = &mut self.data[index as usize];
+                    self.first_free = slot.next;
+                    slot.item = Some(item);
+                    slot.next = -1;
+                    self.elements += 1;
+                },
+                Change::Write(index, item) => {
+                    self.data[index as usize].item = Some(item);
+                },
+            }
+        }
+    }
+
+    fn free_slot(&mut self, n: i32) -> T {
+        let item = self.data[n as usize].item.take().expect("Erasing free slot in FreeList");
+
         self.elements -= 1;
         self.data[n as usize].next = self.first_free;
         self.first_free = n;
+
+        item
+    }
+
+    /// Iterate live items as (index, item) in index order
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            inner: self.data.iter().enumerate()
+        }
+    }
+
+    pub fn iter_mut(&mut self) -> IterMut<'_, T> {
+        IterMut {
+            inner: self.data.iter_mut().enumerate()
+        }
+    }
+
+    /// True if n is the index of an item that has not been erased
+    pub fn is_live(&self, n: i32) -> bool {
+        n >= 0 && (n as usize) < self.data.len() && self.data[n as usize].item.is_some()
+    }
+
+    /// Move all items to the front, keeping their order, and release the free slots.
+    /// Indices change, so anything holding one must be updated with the returned Remap
+    pub fn compact(&mut self) -> Remap {
+        assert!(self.journal.is_none(), "Compacting FreeList while recording");
+
+        let mut new_index = vec![-1; self.data.len()];
+        let mut next = 0;
+
+        for (old, slot) in self.data.iter().enumerate() {
+            if slot.item.is_some() {
+                new_index[old] = next;
+                next += 1;
+            }
+        }
+
+        self.data.retain(|slot| slot.item.is_some());
+        self.data.shrink_to_fit();
+        self.first_free = -1;
+
+        Remap { new_index }
+    }
+
+    /// Move the item at n to the next free slot to reuse, if that is lower, and return where it is now.
+    /// Only items past the first elements_count() slots are moved, they are the ones ['trim()'] can not release.
+    /// The slot at n is not reused until the next trim. Cannot be used while recording
+    pub fn move_down(&mut self, n: i32) -> i32 {
+        assert!(self.journal.is_none(), "Moving in FreeList while recording");
+
+        if n < self.elements || self.first_free == -1 || self.first_free > n {
+            return n;
+        }
+
+        let to = self.first_free;
+        let item = self.data[n as usize].item.take().expect("Moving free slot in FreeList");
+
+        self.first_free = self.data[to as usize].next;
+        self.data[to as usize] = FreeItem { item: Some(item), next: -1 };
+
+        to
+    }
+
+    /// Release the free slots at the end, and reuse the rest lowest first. Cannot be used while recording
+    pub fn trim(&mut self) {
+        assert!(self.journal.is_none(), "Trimming FreeList while recording");
+
+        while self.data.last().is_some_and(|slot| slot.item.is_none()) {
+            self.data.pop();
+        }
+
+        self.first_free = -1;
+
+        for index in (0..self.data.len()).rev() {
+            if self.data[index].item.is_none() {
+                self.data[index].next = self.first_free;
+                self.first_free = index as i32;
+            }
+        }
     }
 
     pub fn clear(&mut self) {
@@ -69,18 +300,115 @@ impl<T : > FreeList<T> {
 }
 
 
+impl<T: Clone> FreeList<T> {
+
+    /// Like ['begin()'], but changes through IndexMut are recorded too, by cloning the item before each change
+    pub fn begin_with_writes(&mut self) {
+        self.begin();
+        self.save = Some(T::clone);
+    }
+}
+
+
+pub struct Iter<'a, T> {
+    inner: std::iter::Enumerate<std::slice::Iter<'a, FreeItem<T>>>
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = (i32, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for (i, e) in &mut self.inner {
+            if let Some(item) = &e.item {
+                return Some((i as i32, item));
+            }
+        }
+        None
+    }
+}
+
+
+pub struct IterMut<'a, T> {
+    inner: std::iter::Enumerate<std::slice::IterMut<'a, FreeItem<T>>>
+}
+
+impl<'a, T> Iterator for IterMut<'a, T> {
+    type Item = (i32, &'a mut T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for (i, e) in &mut self.inner {
+            if let Some(item) = &mut e.item {
+                return Some((i as i32, item));
+            }
+        }
+        None
+    }
+}
+
+
+pub struct IntoIter<T> {
+    inner: std::iter::Enumerate<std::vec::IntoIter<FreeItem<T>>>
+}
+
+impl<T> Iterator for IntoIter<T> {
+    type Item = (i32, T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for (i, e) in &mut self.inner {
+            if let Some(item) = e.item {
+                return Some((i as i32, item));
+            }
+        }
+        None
+    }
+}
+
+
+impl<T> IntoIterator for FreeList<T> {
+    type Item = (i32, T);
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> IntoIter<T> {
+        IntoIter {
+            inner: self.data.into_iter().enumerate()
+        }
+    }
+}
+
+impl<'a, T> IntoIterator for &'a FreeList<T> {
+    type Item = (i32, &'a T);
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
+
+impl<T> Default for FreeList<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
 impl<T> Index<i32> for FreeList<T> {
     type Output = T;
 
-    fn index<'a>(&'a self, i: i32) -> &'a T {
-        &self.data[i as usize].item
+    fn index(&self, i: i32) -> &T {
+        self.data[i as usize].item.as_ref().expect("Index into free slot in FreeList")
     }
 }
 
 
 impl<T> IndexMut<i32> for FreeList<T> {
-    fn index_mut<'a>(&'a mut self, i: i32) -> &'a mut T {
-        &mut self.data[i as usize].item
+    fn index_mut(&mut self, i: i32) -> &mut T {
+        if let (Some(journal), Some(save)) = (&mut self.journal, self.save) {
+            let item = self.data[i as usize].item.as_ref().expect("Index into free slot in FreeList");
+            journal.push(Change::Write(i, save(item)));
+        }
+
+        self.data[i as usize].item.as_mut().expect("Index into free slot in FreeList")
     }
 }
 
@@ -90,10 +418,8 @@ impl<T: std::fmt::Debug> fmt::Debug for FreeList<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut dbg_list = f.debug_list();
 
-         for (i, e) in self.data.iter().enumerate() {
-            if e.next == -1 && self.first_free != i as i32 {
-                dbg_list.entry(&e.item);
-            }
+         for (_, item) in self.iter() {
+            dbg_list.entry(item);
          }
         dbg_list.finish()
 
@@ -127,4 +453,167 @@ mod test {
         let idx = fl.insert(1);
         assert_eq!(idx, 0); // we have deleted all items new idx should be 0
     }
+
+    #[test]
+    fn is_live() {
+        let mut fl = FreeList::new();
+
+        let idx1 = fl.insert(3);
+        let idx2 = fl.insert(4);
+
+        fl.erase(idx2);
+
+        assert!(fl.is_live(idx1));
+        assert!(!fl.is_live(idx2));
+        assert!(!fl.is_live(-1));
+        assert!(!fl.is_live(2));
+
+        assert_eq!(fl.take(idx1), 3);
+        assert!(!fl.is_live(idx1));
+    }
+
+    #[test]
+    fn get() {
+        let mut fl = FreeList::new();
+
+        let idx1 = fl.insert(3);
+        let idx2 = fl.insert(4);
+        let idx3 = fl.insert(5);
+
+        // the last freed slot is free too
+        fl.erase(idx1);
+        fl.erase(idx3);
+
+        assert_eq!(fl.get(idx1), None);
+        assert_eq!(fl.get(idx2), Some(&4));
+        assert_eq!(fl.get(idx3), None);
+        assert_eq!(fl.get(-1), None);
+        assert_eq!(fl.get(3), None);
+
+        *fl.get_mut(idx2).unwrap() = 6;
+        assert_eq!(fl[idx2], 6);
+        assert!(fl.get_mut(idx1).is_none());
+
+        assert_eq!(fl.iter_mut().map(|(i, v)| (i, *v)).collect::<Vec<_>>(), vec![(idx2, 6)]);
+        assert_eq!(format!("{:?}", fl), "[6]");
+        assert!(fl.capacity() >= 3);
+    }
+
+    #[test]
+    fn compact() {
+        let mut fl = FreeList::new();
+
+        for i in 0..6 {
+            fl.insert(i);
+        }
+
+        fl.erase(0);
+        fl.erase(3);
+        fl.erase(4);
+
+        let remap = fl.compact();
+
+        assert_eq!(remap.iter().collect::<Vec<_>>(), vec![(1, 0), (2, 1), (5, 2)]);
+        assert_eq!(remap.get(0), None);
+        assert_eq!(remap.get(5), Some(2));
+        assert_eq!(remap.get(6), None);
+
+        assert_eq!(fl.data_len(), 3);
+        assert_eq!(fl.elements_count(), 3);
+        assert_eq!(fl.iter().map(|(i, &v)| (i, v)).collect::<Vec<_>>(), vec![(0, 1), (1, 2), (2, 5)]);
+
+        // no free slots left
+        assert_eq!(fl.insert(6), 3);
+    }
+
+    #[test]
+    fn move_down_trim() {
+        let mut fl = FreeList::new();
+
+        for i in 0..6 {
+            fl.insert(i);
+        }
+
+        fl.erase(3);
+        fl.erase(0);
+        fl.erase(1);
+
+        // lowest free slot first after trim, and only items past the first 3 slots move
+        fl.trim();
+        assert_eq!(fl.move_down(2), 2);
+        assert_eq!(fl.move_down(5), 0);
+        assert_eq!(fl.move_down(4), 1);
+        assert_eq!(fl.data_len(), 6);
+
+        // the slots moved from are not reused before trim
+        assert_eq!(fl.insert(6), 3);
+        fl.erase(3);
+
+        fl.trim();
+        assert_eq!(fl.data_len(), 3);
+        assert_eq!(fl.iter().map(|(i, &v)| (i, v)).collect::<Vec<_>>(), vec![(0, 5), (1, 4), (2, 2)]);
+        assert_eq!(fl.insert(7), 3);
+    }
+
+    #[test]
+    fn rollback() {
+        let mut fl = FreeList::new();
+
+        for i in 0..4 {
+            fl.insert(i);
+        }
+
+        fl.erase(1);
+        fl.erase(2);
+
+        fl.begin();
+
+        assert_eq!(fl.insert(10), 2);
+        fl.erase(0);
+        fl.erase(3);
+        assert_eq!(fl.insert(11), 3);
+        assert_eq!(fl.insert(12), 0);
+        assert_eq!(fl.insert(13), 1);
+        assert_eq!(fl.insert(14), 4);
+
+        fl.rollback();
+
+        assert_eq!(fl.elements_count(), 2);
+        assert_eq!(fl.data_len(), 4);
+        assert_eq!(fl.iter().map(|(i, &v)| (i, v)).collect::<Vec<_>>(), vec![(0, 0), (3, 3)]);
+
+        // free slots in the same order as before begin
+        assert_eq!(fl.insert(20), 2);
+        assert_eq!(fl.insert(21), 1);
+        assert_eq!(fl.insert(22), 4);
+    }
+
+
+    #[test]
+    fn rollback_writes() {
+        let mut fl = FreeList::new();
+
+        let idx1 = fl.insert(1);
+        let idx2 = fl.insert(2);
+
+        fl.begin_with_writes();
+
+        fl[idx1] = 10;
+        fl[idx1] += 1;
+        fl.erase(idx2);
+
+        let idx3 = fl.insert(3);
+        fl[idx3] = 30;
+
+        fl.rollback();
+
+        assert_eq!(fl.iter().map(|(i, &v)| (i, v)).collect::<Vec<_>>(), vec![(idx1, 1), (idx2, 2)]);
+
+        // writes are not recorded after rollback
+        fl[idx1] = 5;
+        fl.begin();
+        fl[idx2] = 6;
+        fl.rollback();
+        assert_eq!(fl.iter().map(|(i, &v)| (i, v)).collect::<Vec<_>>(), vec![(idx1, 5), (idx2, 6)]);
+    }
 }
@@ -0,0 +1,199 @@
+use crate::free_list::FreeList;
+use crate::*;
+
+
+/// Index that divides bounds into square cells of the same size, and lists each element in every cell it touches.
+/// Good when elements are about the size of a cell and spread evenly. Elements outside bounds are put
+/// in the cells on the edge, so they are still found, just not fast
+pub struct UniformGrid<T> {
+
+    elements: FreeList<(Rect, T)>,
+
+    // Ids of the elements touching each cell, row by row from the bottom
+    cells: Vec::<Vec::<ElementId>>,
+
+    bounds: Rect,
+    cell_size: i32,
+    columns: i32,
+    rows: i32,
+}
+
+
+// Public interface
+impl<T> UniformGrid<T> {
+
+    pub fn new(bounds: Rect, cell_size: i32) -> Self {
+
+        let cell_size = i32::max(1, cell_size);
+        let columns = (bounds.right - bounds.left) / cell_size + 1;
+        let rows = (bounds.top - bounds.bottom) / cell_size + 1;
+
+        UniformGrid {
+            elements: FreeList::new(),
+            cells: (0..columns * rows).map(|_| vec![]).collect(),
+            bounds,
+            cell_size,
+            columns,
+            rows,
+        }
+    }
+
+    pub fn bounds(&self) -> Rect {
+        self.bounds
+    }
+
+    pub fn cell_size(&self) -> i32 {
+        self.cell_size
+    }
+}
+
+
+impl<T> SpatialIndex<T> for UniformGrid<T> {
+
+    fn insert(&mut self, element: T, rect: Rect) -> Result<ElementId, QuadTreeError> {
+        let element_id = self.elements.insert((rect, element));
+        self.link(element_id, rect);
+
+        Ok(element_id)
+    }
+
+    fn remove(&mut self, element_id: ElementId) -> Option<T> {
+        if !self.elements.is_live(element_id) {
+            return None;
+        }
+
+        self.unlink(element_id, self.elements[element_id].0);
+
+        Some(self.elements.take(element_id).1)
+    }
+
+    fn update(&mut self, element_id: ElementId, rect: Rect) -> Result<(), QuadTreeError> {
+        if !self.elements.is_live(element_id) {
+            return Err(QuadTreeError::InvalidElement(element_id));
+        }
+
+        self.unlink(element_id, self.elements[element_id].0);
+        self.elements[element_id].0 = rect;
+        self.link(element_id, rect);
+
+        Ok(())
+    }
+
+    // Elements in more than one cell are only reported in the cell with the top left corner of where
+    // element and rect overlap, like QuadTree::query_with
+    fn query_rect(&self, rect: Rect, output: &mut Vec<ElementId>) {
+
+        let (columns, rows) = self.cell_range(rect);
+
+        for row in rows {
+            for column in columns.clone() {
+                for &element_id in &self.cells[(row * self.columns + column) as usize] {
+                    let element_rect = self.elements[element_id].0;
+
+                    if !rect.intersect(element_rect) {
+                        continue;
+                    }
+
+                    let overlap = element_rect.intersection(rect);
+
+                    if self.cell(Point::new(overlap.left, overlap.top)) == (column, row) {
+                        output.push(element_id);
+                    }
+                }
+            }
+        }
+    }
+
+    // Search rings of cells around the cell of point. Cells in ring r are at least r - 1 cells away,
+    // also when point is outside bounds, so stop when that is further than the closest found
+    fn nearest(&self, point: Point) -> Option<ElementId> {
+
+        let (column, row) = self.cell(point);
+        let mut best: Option<(i64, ElementId)> = None;
+
+        for r in 0..i32::max(self.columns, self.rows) {
+
+            if let Some((best_distance, _)) = best {
+                let min_distance = (r as i64 - 1) * self.cell_size as i64;
+
+                if r > 0 && min_distance * min_distance > best_distance {
+                    break;
+                }
+            }
+
+            for ring_row in i32::max(0, row - r)..=i32::min(self.rows - 1, row + r) {
+                for ring_column in i32::max(0, column - r)..=i32::min(self.columns - 1, column + r) {
+
+                    if (ring_row - row).abs() != r && (ring_column - column).abs() != r {
+                        continue;
+                    }
+
+                    for &element_id in &self.cells[(ring_row * self.columns + ring_column) as usize] {
+                        let candidate = (self.elements[element_id].0.distance_squared(point), element_id);
+
+                        if best.is_none_or(|b| candidate < b) {
+                            best = Some(candidate);
+                        }
+                    }
+                }
+            }
+        }
+
+        best.map(|(_, element_id)| element_id)
+    }
+
+    fn elements(&self) -> Box<dyn Iterator<Item = (ElementId, Rect, &T)> + '_> {
+        Box::new(self.elements.iter().map(|(element_id, (rect, element))| (element_id, *rect, element)))
+    }
+
+    fn len(&self) -> usize {
+        self.elements.elements_count() as usize
+    }
+}
+
+
+// Private functions
+impl<T> UniformGrid<T> {
+
+    // Column and row of the cell containing point, the closest cell if outside bounds
+    fn cell(&self, point: Point) -> (i32, i32) {
+        let column = (point.x - self.bounds.left).div_euclid(self.cell_size);
+        let row = (point.y - self.bounds.bottom).div_euclid(self.cell_size);
+
+        (column.clamp(0, self.columns - 1), row.clamp(0, self.rows - 1))
+    }
+
+
+    fn cell_range(&self, rect: Rect) -> (std::ops::Range<i32>, std::ops::Range<i32>) {
+        let (left, bottom) = self.cell(Point::new(rect.left, rect.bottom));
+        let (right, top) = self.cell(Point::new(rect.right, rect.top));
+
+        (left..right + 1, bottom..top + 1)
+    }
+
+
+    fn link(&mut self, element_id: ElementId, rect: Rect) {
+        let (columns, rows) = self.cell_range(rect);
+
+        for row in rows {
+            for column in columns.clone() {
+                self.cells[(row * self.columns + column) as usize].push(element_id);
+            }
+        }
+    }
+
+
+    fn unlink(&mut self, element_id: ElementId, rect: Rect) {
+        let (columns, rows) = self.cell_range(rect);
+
+        for row in rows {
+            for column in columns.clone() {
+                let cell = &mut self.cells[(row * self.columns + column) as usize];
+
+                if let Some(i) = cell.iter().position(|&id| id == element_id) {
+                    cell.swap_remove(i);
+                }
+            }
+        }
+    }
+}
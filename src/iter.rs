@@ -0,0 +1,111 @@
+use crate::free_list::{self, FreeList};
+use crate::*;
+
+
+// Iterators over all live elements in a QuadTree, in element id order.
+// Each element is paired with data through its data_id, not by position in the free lists
+
+pub struct Iter<'a, T> {
+    elm_rects: free_list::Iter<'a, ElmRect>,
+    data: &'a FreeList<T>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = (ElementId, Rect, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (element_id, elm) = self.elm_rects.next()?;
+
+        Some((element_id, elm.rect, &self.data[elm.data_id]))
+    }
+}
+
+
+// The data is split into one slot per data id up front, so each element can take its own
+pub struct IterMut<'a, T> {
+    elm_rects: free_list::Iter<'a, ElmRect>,
+    data: Vec::<Option<&'a mut T>>,
+}
+
+impl<'a, T> Iterator for IterMut<'a, T> {
+    type Item = (ElementId, Rect, &'a mut T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (element_id, elm) = self.elm_rects.next()?;
+        let data = self.data[elm.data_id as usize].take().expect("Element without data");
+
+        Some((element_id, elm.rect, data))
+    }
+}
+
+
+pub struct IntoIter<T> {
+    elm_rects: free_list::IntoIter<ElmRect>,
+    data: Vec::<Option<T>>,
+}
+
+impl<T> Iterator for IntoIter<T> {
+    type Item = (ElementId, Rect, T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (element_id, elm) = self.elm_rects.next()?;
+        let data = self.data[elm.data_id as usize].take().expect("Element without data");
+
+        Some((element_id, elm.rect, data))
+    }
+}
+
+
+// Items of a free list in a vec indexed by their index in the list
+fn by_index<T, I: Iterator<Item = (i32, T)>>(len: i32, items: I) -> Vec::<Option<T>> {
+    let mut res: Vec::<Option<T>> = (0..len).map(|_| None).collect();
+
+    for (index, item) in items {
+        res[index as usize] = Some(item);
+    }
+
+    res
+}
+
+
+impl<T, A: Aggregate<T>> QuadTree<T, A> {
+
+    /// Iterate all elements as (id, rect, element) in id order
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            elm_rects: self.elm_rects.iter(),
+            data: &self.data,
+        }
+    }
+
+    pub fn iter_mut(&mut self) -> IterMut<'_, T> {
+        IterMut {
+            elm_rects: self.elm_rects.iter(),
+            data: by_index(self.data.data_len(), self.data.iter_mut()),
+        }
+    }
+}
+
+
+impl<T, A> IntoIterator for QuadTree<T, A> {
+    type Item = (ElementId, Rect, T);
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> IntoIter<T> {
+        let len = self.data.data_len();
+
+        IntoIter {
+            elm_rects: self.elm_rects.into_iter(),
+            data: by_index(len, self.data.into_iter()),
+        }
+    }
+}
+
+impl<'a, T, A: Aggregate<T>> IntoIterator for &'a QuadTree<T, A> {
+    type Item = (ElementId, Rect, &'a T);
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
@@ -0,0 +1,265 @@
+use crate::*;
+use crate::free_list::Remap;
+
+
+impl<T, A: Aggregate<T>> QuadTree<T, A> {
+
+    /// Move nodes, element nodes, elements and data to the front of their storage and release the rest.
+    /// Use after many removes, the storage never shrinks on its own.
+    /// Element ids change, the returned Remap gives the new id of every element.
+    /// The observer sees every element leave its leaves, the nodes that moved, and the elements enter the leaves again with the new indices
+    pub fn compact(&mut self) -> Remap {
+
+        for leaf in self.all_leaves() {
+            self.notify_leave_all(leaf.node_index);
+        }
+
+        // items keep their order, so the 4 children of a node stay next to each other,
+        // and data stays at the same index as its element
+        let nodes = self.nodes.compact();
+        let element_nodes = self.element_nodes.compact();
+        let elements = self.elm_rects.compact();
+        let data = self.data.compact();
+
+        for (_, node) in self.nodes.iter_mut() {
+            if node.is_branch() {
+                node.first_child = nodes.get(node.first_child).expect("Branch with free children");
+            }
+            else if node.first_child != -1 {
+                node.first_child = element_nodes.get(node.first_child).expect("Leaf with free element node");
+            }
+        }
+
+        for (_, elm_node) in self.element_nodes.iter_mut() {
+            if elm_node.next != -1 {
+                elm_node.next = element_nodes.get(elm_node.next).expect("Free element node in list");
+            }
+            elm_node.elm_id = elements.get(elm_node.elm_id).expect("Element node of removed element");
+        }
+
+        for (_, elm) in self.elm_rects.iter_mut() {
+            elm.data_id = data.get(elm.data_id).expect("Element without data");
+        }
+
+        self.query_tmp_buffer = vec![];
+        self.node_aggregates = vec![];
+        self.node_counts = vec![];
+        self.recompute_aggregates();
+
+        let moved: Vec::<(i32, i32)> = nodes.iter().filter(|&(old, new)| old != new).collect();
+
+        if !moved.is_empty() {
+            self.notify(|o| o.on_nodes_moved(&moved));
+        }
+
+        for leaf in self.all_leaves() {
+            self.notify_enter_all(leaf.node_index);
+        }
+
+        elements
+    }
+
+
+    /// Rebuild nodes in depth first order, with the 4 children of a node next to each other,
+    /// and the element nodes of each leaf as one run. Use on a tree that is built once and queried many times,
+    /// where removes and reuse of free slots have scattered the nodes. Element ids do not change.
+    /// The observer is told about the nodes that moved as in ['compact()']
+    pub fn optimize_layout(&mut self) {
+
+        for leaf in self.all_leaves() {
+            self.notify_leave_all(leaf.node_index);
+        }
+
+        let mut nodes = BlockList::new(Node::leaf());
+        let mut element_nodes = FreeList::new();
+        let mut moved = vec![];
+
+        self.layout_node(0, 0, &mut nodes, &mut element_nodes, &mut moved);
+
+        self.nodes = nodes;
+        self.element_nodes = element_nodes;
+
+        self.node_aggregates = vec![];
+        self.node_counts = vec![];
+        self.recompute_aggregates();
+
+        if !moved.is_empty() {
+            self.notify(|o| o.on_nodes_moved(&moved));
+        }
+
+        for leaf in self.all_leaves() {
+            self.notify_enter_all(leaf.node_index);
+        }
+    }
+
+
+    // Copy old node into new, placing children after everything copied so far. Nodes that get a new index are added to moved
+    fn layout_node(&self, old: i32, new: i32, nodes: &mut BlockList<Node>, element_nodes: &mut FreeList<ElmRectNode>, moved: &mut Vec::<(i32, i32)>) {
+
+        if old != new {
+            moved.push((old, new));
+        }
+
+        let node = &self.nodes[old];
+
+        if node.is_branch() {
+            let first_child = nodes.alloc_block([Node::leaf(), Node::leaf(), Node::leaf(), Node::leaf()]);
+
+            nodes[new] = Node { first_child, count: -1 };
+
+            for i in 0..4 {
+                self.layout_node(node.first_child + i, first_child + i, nodes, element_nodes, moved);
+            }
+
+            return;
+        }
+
+        // same order as the old list
+        let mut first = -1;
+        let mut prev = -1;
+        let mut elm_node_index = node.first_child;
+
+        while elm_node_index != -1 {
+            let elm_node = &self.element_nodes[elm_node_index];
+            let index = element_nodes.insert(ElmRectNode { next: -1, elm_id: elm_node.elm_id });
+
+            if prev == -1 {
+                first = index;
+            }
+            else {
+                element_nodes[prev].next = index;
+            }
+
+            prev = index;
+            elm_node_index = elm_node.next;
+        }
+
+        nodes[new] = Node { first_child: first, count: node.count };
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn compact_keeps_elements() {
+
+        let rect = Rect::from_points(Point {x: 0, y: 0}, Point { x: 256, y: 256} );
+
+        let mut qt = QuadTree::new(rect);
+        qt.set_elements_per_node(4);
+
+        let mut ids = vec![];
+        for i in 0..200 {
+            ids.push((i, qt.insert(i, Rect::new(i, i + 10, 3, 3))));
+        }
+
+        // churn
+        ids.retain(|&(i, id)| {
+            if i % 3 != 0 {
+                qt.remove(id);
+            }
+            i % 3 == 0
+        });
+        qt.cleanup();
+
+        let nodes_before = qt.nodes.data_len();
+        let query_r = Rect::new(20, 200, 150, 150);
+
+        let mut before = vec![];
+        qt.query_refs(query_r, -1, &mut before);
+        let mut before: Vec<i32> = before.into_iter().copied().collect();
+        before.sort();
+
+        let remap = qt.compact();
+
+        assert!(qt.nodes.data_len() <= nodes_before);
+        assert_eq!(qt.elm_rects.data_len() as usize, ids.len());
+        assert_eq!(qt.data.data_len() as usize, ids.len());
+        assert_eq!(qt.element_nodes.data_len(), qt.element_nodes.elements_count());
+
+        for &(i, id) in &ids {
+            let new_id = remap.get(id).unwrap();
+            assert_eq!(qt.get(new_id), Some(&i));
+            assert_eq!(qt.rect(new_id), Some(Rect::new(i, i + 10, 3, 3)));
+        }
+
+        let mut after = vec![];
+        qt.query_refs(query_r, -1, &mut after);
+        let mut after: Vec<i32> = after.into_iter().copied().collect();
+        after.sort();
+
+        assert_eq!(before, after);
+        assert_eq!(qt.count_in(query_r), after.len());
+
+        // still works as a tree
+        let id = qt.insert(1000, Rect::new(5, 250, 1, 1));
+        qt.remove(remap.get(ids[0].1).unwrap());
+        assert_eq!(qt.get(id), Some(&1000));
+        assert_eq!(qt.len(), ids.len());
+    }
+
+
+    #[test]
+    fn optimize_layout_is_depth_first() {
+
+        let rect = Rect::from_points(Point {x: 0, y: 0}, Point { x: 256, y: 256} );
+
+        let mut qt = QuadTree::new(rect);
+        qt.set_elements_per_node(4);
+
+        let mut ids = vec![];
+        for i in 0..300 {
+            ids.push(qt.insert(i, Rect::new((i * 37) % 250, (i * 91) % 250 + 5, 2, 2)));
+        }
+
+        // scatter nodes through free slots
+        for &id in ids.iter().step_by(2) {
+            qt.remove(id);
+        }
+        qt.cleanup();
+        for i in 0..150 {
+            qt.insert(1000 + i, Rect::new((i * 53) % 250, (i * 17) % 250 + 5, 2, 2));
+        }
+
+        let query_r = Rect::new(30, 220, 120, 160);
+        let mut before = vec![];
+        qt.query_ids(query_r, -1, &mut before);
+        before.sort();
+
+        qt.optimize_layout();
+
+        let mut after = vec![];
+        qt.query_ids(query_r, -1, &mut after);
+        after.sort();
+
+        assert_eq!(before, after);
+        assert_eq!(qt.count_in(query_r), after.len());
+        assert_eq!(qt.nodes.data_len(), qt.nodes.elements_count());
+        assert_eq!(qt.element_nodes.data_len(), qt.element_nodes.elements_count());
+
+        // children come after their parent, and every leaf's elements are one run
+        for (index, node) in qt.nodes.iter() {
+            if node.is_branch() {
+                assert!(node.first_child > index);
+            }
+            else {
+                let mut elm_node_index = node.first_child;
+                while elm_node_index != -1 {
+                    let next = qt.element_nodes[elm_node_index].next;
+                    assert!(next == -1 || next == elm_node_index + 1);
+                    elm_node_index = next;
+                }
+            }
+        }
+
+        // still works as a tree
+        let id = qt.insert(5000, Rect::new(100, 100, 1, 1));
+        qt.remove(ids[1]);
+        assert_eq!(qt.get(id), Some(&5000));
+        assert_eq!(qt.len(), 300);
+    }
+}
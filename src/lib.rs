@@ -1,26 +1,56 @@
 use std::fmt;
 pub mod free_list;
 use free_list::FreeList;
+use block_list::BlockList;
 use std::collections::VecDeque;
 
 mod query;
 mod data;
+mod point_tree;
+mod linear;
+mod region;
+mod error;
+mod iter;
+mod aggregate;
+mod density;
+mod persistent;
+mod transaction;
+mod observer;
+mod spatial_index;
+mod grid;
+mod brute_force;
+mod layout;
+mod block_list;
+mod maintain;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 
 pub use self::data::*;
 pub use self::query::*;
+pub use self::point_tree::*;
+pub use self::linear::*;
+pub use self::region::*;
+pub use self::error::*;
+pub use self::iter::*;
+pub use self::aggregate::*;
+pub use self::density::*;
+pub use self::persistent::*;
+pub use self::transaction::*;
+pub use self::observer::*;
+pub use self::spatial_index::*;
+pub use self::grid::*;
+pub use self::brute_force::*;
 
 // From answer here: https://stackoverflow.com/questions/41946007/efficient-and-well-explained-implementation-of-a-quadtree-for-2d-collision-dte
 
-use data::*;
-use query::*;
-
 struct FindLeaves {
     node_id: i32,
     rect: Rect,
     depth: i32
 }
 
-pub struct QuadTree<T>{
+pub struct QuadTree<T, A = ()>{
 
     // All quads of elements in the quadtree
     elm_rects: FreeList<ElmRect>,
@@ -33,9 +63,8 @@ pub struct QuadTree<T>{
     // All nodes in quadTree
     // First node is the root
     // leafs are where count > 0 and then first child is index into element_nodes
-    // Has to be vec, since otherwise we cannot guarantee that we can get 4 consecutive nodes
-    // Or can we??
-    nodes: FreeList::<Node>,
+    // Children are allocated 4 at a time, so they are always consecutive
+    nodes: BlockList::<Node>,
 
 
     // Actual data inserted into tree
@@ -48,23 +77,63 @@ pub struct QuadTree<T>{
     max_depth: i32,
     elements_per_node: i32,
 
+    // Grow root instead of dropping elements outside it
+    auto_grow: bool,
+
+    // Quad the old root became for each time the root has grown, last is the latest
+    grown_from: Vec::<usize>,
+
     // buffer for storing querying, to store elements already found
     query_tmp_buffer: Vec::<bool>,
+
+    // Aggregate of the elements anchored in the subtree of each node, indexed like nodes.
+    // See aggregate.rs
+    node_aggregates: Vec::<A>,
+
+    // Number of elements anchored in the subtree of each node, kept like node_aggregates
+    // but also for trees without an aggregate
+    node_counts: Vec::<i32>,
+
+    // Old aggregates and counts while in a transaction
+    aggregate_journal: Option<AggregateJournal<A>>,
+
+    // Leaves split by the current insert, with their rects
+    split_log: Vec::<(i32, Rect)>,
+
+    // Told about structural changes, see observer.rs
+    observer: Option<Box<dyn TreeObserver + Send + Sync>>,
+
+    // Events held back from the observer during a transaction
+    held_events: Option<EventLog>,
+
+    // Next node maintain looks at, see maintain.rs
+    maintain_cursor: i32,
+
+    // Whether the current maintain pass changed anything
+    maintain_changed: bool,
+
+    // Set when a maintain pass changed nothing, cleared by anything that links, unlinks or frees nodes.
+    // maintain has nothing to do while it is set
+    maintain_idle: bool,
 }
 
 
 
-// Public interface
-impl<'a, T: std::fmt::Debug> QuadTree<T> {
+impl<T> QuadTree<T> {
 
     pub fn new(rect: Rect) -> Self {
+        Self::with_aggregate(rect)
+    }
+}
 
-        let mut nodes = FreeList::new();
 
-        nodes.insert(Node {
-            first_child: -1,
-            count: 0,
-        });
+// Public interface
+impl<T, A: Aggregate<T>> QuadTree<T, A> {
+
+    /// New tree that keeps an aggregate of type A for every node, see ['aggregate_in()']
+    pub fn with_aggregate(rect: Rect) -> Self {
+
+        let nodes = BlockList::new(Node::leaf());
 
         QuadTree {
             elm_rects: FreeList::new(),
@@ -74,82 +143,110 @@ impl<'a, T: std::fmt::Debug> QuadTree<T> {
             root_rect: rect,
             max_depth: 10,
             elements_per_node: 300,
+            auto_grow: false,
+            grown_from: vec![],
             query_tmp_buffer: vec![],
+            node_aggregates: vec![],
+            node_counts: vec![],
+            aggregate_journal: None,
+            split_log: vec![],
+            observer: None,
+            held_events: None,
+            maintain_cursor: 0,
+            maintain_changed: true,
+            maintain_idle: false,
         }
     }
 
     pub fn insert(&mut self, element: T, element_rect: Rect) ->  i32 {
+        self.insert_with_margin(element, element_rect, 0)
+    }
 
-        //println!("inserting {:?}", element_rect);
-        // check if we can insert into root
-        let data_id = self.data.insert(element);
-
-        let element_id = self.elm_rects.insert(ElmRect {
-            data_id: data_id,
-            rect: element_rect.clone()
-        });
-
-        //println!("Inserting node for element with id: {:?}", element_id);
-        self.node_insert(element_id, 0, self.root_rect, 0);
-
-        element_id
+    /// Insert an element that moves often. It is linked into the leaves of its rect expanded by margin,
+    /// and ['update()'] only relinks it when the new rect is outside that. Queries still use the rect itself
+    pub fn insert_dynamic(&mut self, element: T, element_rect: Rect, margin: i32) -> ElementId {
+        self.insert_with_margin(element, element_rect, i32::max(0, margin))
     }
 
-    pub fn set_elements_per_node(&mut self, npc: i32) {
-        self.elements_per_node = i32::max(1, npc);
+    /// Like insert, but fails instead of storing an element no query can find
+    pub fn try_insert(&mut self, element: T, element_rect: Rect) -> Result<ElementId, QuadTreeError> {
+        self.check_rect(element_rect)?;
+
+        Ok(self.insert(element, element_rect))
     }
 
+    /// Move element to a new rect. Elements from ['insert_dynamic()'] stay in their leaves
+    /// while the new rect is inside the expanded rect
+    pub fn update(&mut self, element_id: ElementId, element_rect: Rect) {
+        self.fit_root(element_rect);
 
-    /// Removes an element from the tree. Does not restructure the tree see ['cleanup()']
-    pub fn remove(&mut self, element_id: i32) {
         let elm = &self.elm_rects[element_id];
-        let leaves = self.find_leaves(0, self.root_rect, elm.rect, 0);
 
-        for &leaf in &leaves {
-            let leaf_index = leaf.node_index;
-            let leaf_node = &mut self.nodes[leaf_index];
+        if elm.fat.contains(element_rect) {
+            let old = elm.rect;
+            self.elm_rects[element_id].rect = element_rect;
+            self.aggregate_moved(element_id, old);
+            return;
+        }
 
-            let mut element_index = leaf_node.first_child;
-            let mut prev_index = -1;
+        self.unlink(element_id);
+        self.aggregate_unlinked(self.elm_rects[element_id].rect);
 
-            // only walk until we find element
-            while element_index != -1 && self.element_nodes[element_index].elm_id != element_id {
-                prev_index = element_index;
-                element_index = self.element_nodes[element_index].next;
+        let elm = &mut self.elm_rects[element_id];
+        elm.rect = element_rect;
+        elm.fat = element_rect.expand(elm.margin);
 
-            }
+        self.node_insert(element_id, 0, self.root_rect, 0);
+        self.aggregate_linked(element_id);
+    }
 
-            if element_index != -1 {  // elment found at element_index
-                assert_eq!(self.element_nodes[element_index].elm_id, element_id);
+    pub fn try_update(&mut self, element_id: ElementId, element_rect: Rect) -> Result<(), QuadTreeError> {
+        self.check_element(element_id)?;
+        self.check_rect(element_rect)?;
 
+        self.update(element_id, element_rect);
+        Ok(())
+    }
 
-                let next_index = self.element_nodes[element_index].next;
+    pub fn set_elements_per_node(&mut self, npc: i32) {
+        self.elements_per_node = i32::max(1, npc);
+    }
 
-                if prev_index == -1 { // found element is first child, so se node to next
-                    self.nodes[leaf_index].first_child = next_index;
-                } else {
-                    // in middle set
-                    self.element_nodes[prev_index].next = next_index;
-                }
+    /// When enabled, inserting an element that is not inside the root grows the root until it is.
+    /// Each time the root grows it doubles in size, and the old root becomes one of its quads.
+    /// Otherwise elements outside the root are not stored in any leaf.
+    /// The root must have a width and height larger than 0, and can not grow past the range of i32.
+    /// When it can not grow to contain a rect, insert and update panic without changing the tree
+    pub fn set_auto_grow(&mut self, auto_grow: bool) {
+        self.auto_grow = auto_grow;
+    }
 
-                //println!("{:?}", (prev_index, element_index, next_index));
-                self.element_nodes.erase(element_index);
-                self.nodes[leaf_index].count -= 1;
-            }
-        }
+    pub fn root_rect(&self) -> Rect {
+        self.root_rect
+    }
 
-        // also data? but that could be slow???
-        self.data.erase(self.elm_rects[element_id].data_id);
-        self.elm_rects.erase(element_id);
 
+    /// Removes an element from the tree. Does not restructure the tree see ['cleanup()']
+    pub fn remove(&mut self, element_id: i32) {
+        self.remove_element(element_id);
     }
 
+    /// Like remove, but returns the element, or an error if element_id is not in the tree
+    pub fn try_remove(&mut self, element_id: ElementId) -> Result<T, QuadTreeError> {
+        self.check_element(element_id)?;
 
+        Ok(self.remove_element(element_id))
+    }
 
 
     /// Clean the tree by making branches with only empty leaf children into leafs
     /// Only does one level per call.
     pub fn cleanup(&mut self) {
+        self.cleanup_level();
+    }
+
+    // One level of cleanup, returns the number of branches merged
+    fn cleanup_level(&mut self) -> usize {
 
         let mut to_process = VecDeque::new();
 
@@ -183,30 +280,25 @@ impl<'a, T: std::fmt::Debug> QuadTree<T> {
 
         }
 
-        for node_id in to_delete {
-            let mut branch = &mut self.nodes[node_id];
-            let first_child = branch.first_child;
-
-            // -1 for no children
-            // count = 0 for leaf
-            branch.first_child = -1;
-            branch.count = 0;
-
-            // Delete order is important. Since last to be deleted
-            // is also first to be reused in free list. Thus delete in this order
-            // ensure that when we split next time, we still get 4 consecutive nodes
-            self.nodes.erase(first_child + 3);
-            self.nodes.erase(first_child + 2);
-            self.nodes.erase(first_child + 1);
-            self.nodes.erase(first_child );
-
+        for &node_id in &to_delete {
+            self.merge_children(node_id);
         }
+
+        to_delete.len()
     }
 
     /// Clear all data from the tree. Does not clear the structure. So inserting roughly the same data is
     /// fast since we already have the nodes ready
     pub fn clear(&mut self) {
 
+        self.maintain_idle = false;
+
+        if self.observer.is_some() {
+            for leaf in self.find_leaves(0, self.root_rect, self.root_rect, 0) {
+                self.notify_leave_all(leaf.node_index);
+            }
+        }
+
         self.elm_rects.clear();
         //self.element_nodes.clear();
         self.data.clear();
@@ -217,411 +309,1569 @@ impl<'a, T: std::fmt::Debug> QuadTree<T> {
 
         // using len is not correct. Since that is active elements
         for i in 0..self.nodes.data_len() {
-            if self.nodes[i].is_leaf() {
+            if self.nodes.is_live(i) && self.nodes[i].is_leaf() {
                 self.nodes[i].count = 0;
                 self.nodes[i].first_child = -1;
             }
         }
+
+        self.recompute_aggregates();
     }
 
     pub fn max_element_id(&self) -> usize {
         self.elm_rects.data_len() as usize
     }
-}
 
-#[derive(Copy, Clone, Debug)]
-pub struct Leaf {
-    pub node_index: i32,
-    pub depth: i32,
-    pub rect: Rect,
-}
+    /// Remove all elements intersecting rect, and return them as (id, rect, element).
+    /// Does not restructure the tree see ['cleanup()']
+    pub fn drain_in(&mut self, rect: Rect) -> impl Iterator<Item = (ElementId, Rect, T)> {
 
-struct InsertProcess {
-    element_id: i32,
-    node_index: i32,
-    node_rect: Rect,
-    depth: i32
-}
-// Private functions
-impl<'a, T: std::fmt::Debug> QuadTree<T> {
+        let removed = self.unlink_in(rect);
 
+        let mut res = Vec::with_capacity(removed.len());
 
-    fn find_leaves(&self, node_index: i32, node_rect: Rect, search_rect: Rect, depth: i32) -> Vec::<Leaf> {
-        let mut res = vec![];
+        for element_id in removed {
+            let elm = self.elm_rects.take(element_id);
+            let data = self.data.take(elm.data_id);
 
+            res.push((element_id, elm.rect, data));
+        }
 
-        // start at input node, at branches see which overlaps with rect, process those too
-        // return vec of nodes that rect overlaps
-        let mut to_process = VecDeque::new();
+        res.into_iter()
+    }
 
-        //0 is root
-        to_process.push_back(FindLeaves{ node_id: node_index, rect: node_rect, depth});
+    /// Remove all elements where f returns false. f is called once for each element, even
+    /// when it is in more than one leaf. Returns the number removed.
+    /// Does not restructure the tree, so call ['cleanup()'] after if anything was removed
+    pub fn retain<F>(&mut self, mut f: F) -> usize where F: FnMut(ElementId, &Rect, &mut T) -> bool {
 
+        const UNKNOWN: u8 = 0;
+        const KEEP: u8 = 1;
+        const REMOVE: u8 = 2;
 
-        while let Some(node_data) = to_process.pop_front() {
+        self.maintain_idle = false;
 
+        let mut decisions = vec![UNKNOWN; self.elm_rects.data_len() as usize];
 
-            let node = &self.nodes[node_data.node_id];
+        let leaves = self.find_leaves(0, self.root_rect, self.root_rect, 0);
 
-            // if node is a leaf, push to result
-            if self.nodes[node_data.node_id].count != -1 {
-                assert!(self.nodes[node_data.node_id].count != -1);
+        for &leaf in &leaves {
 
-                res.push(Leaf{ node_index: node_data.node_id, depth: node_data.depth, rect: node_data.rect });
+            let mut element_index = self.nodes[leaf.node_index].first_child;
+            let mut prev_index = -1;
+
+            while element_index != -1 {
+                let element_id = self.element_nodes[element_index].elm_id;
+                let next_index = self.element_nodes[element_index].next;
+
+                if decisions[element_id as usize] == UNKNOWN {
+                    let elm = &self.elm_rects[element_id];
+                    let keep = f(element_id, &elm.rect, &mut self.data[elm.data_id]);
+
+                    decisions[element_id as usize] = if keep { KEEP } else { REMOVE };
+                }
+
+                if decisions[element_id as usize] == KEEP {
+                    prev_index = element_index;
+                    element_index = next_index;
+                    continue;
+                }
+
+                if prev_index == -1 {
+                    self.nodes[leaf.node_index].first_child = next_index;
+                } else {
+                    self.element_nodes[prev_index].next = next_index;
+                }
+
+                self.element_nodes.erase(element_index);
+                self.nodes[leaf.node_index].count -= 1;
+                self.notify(|o| o.on_element_leave_leaf(element_id, leaf.node_index));
+
+                element_index = next_index;
             }
-            else {
+        }
 
-                let locations = node_data.rect.location_quads();
+        let mut removed = 0;
 
-                for i in 0..4 {
-                    if locations[i].intersect(search_rect) {
-                        to_process.push_back(FindLeaves { node_id: node.first_child + i as i32,
-                                                          rect: locations[i],
-                                                          depth: node_data.depth + 1 });
+        for element_id in 0..self.elm_rects.data_len() {
+            if !self.elm_rects.is_live(element_id) {
+                continue;
+            }
 
-                    }
+            // elements outside the root are not in any leaf
+            if decisions[element_id as usize] == UNKNOWN {
+                let elm = &self.elm_rects[element_id];
+                if !f(element_id, &elm.rect, &mut self.data[elm.data_id]) {
+                    decisions[element_id as usize] = REMOVE;
                 }
             }
 
+            if decisions[element_id as usize] == REMOVE {
+                self.data.erase(self.elm_rects[element_id].data_id);
+                self.elm_rects.erase(element_id);
+                removed += 1;
+            }
         }
 
+        self.recompute_aggregates();
 
-        res
+        removed
     }
 
+    /// Like ['retain()'], and then cleanup until no branch has only empty leaves, when anything was removed
+    pub fn retain_and_cleanup<F>(&mut self, f: F) -> usize where F: FnMut(ElementId, &Rect, &mut T) -> bool {
 
+        let removed = self.retain(f);
 
+        if removed > 0 {
+            while self.cleanup_level() > 0 {}
+        }
 
-    // insert element_id(index into self.elm_rects) into the node with the given index
-    fn node_insert(&mut self, element_id: i32, node_index: i32, node_rect: Rect, depth: i32) {
+        removed
+    }
 
+    /// Remove all elements intersecting rect, returns the number removed.
+    /// Does not restructure the tree see ['cleanup()']
+    pub fn remove_in(&mut self, rect: Rect) -> usize {
 
-        let mut to_process = VecDeque::new();
+        let removed = self.unlink_in(rect);
 
-        to_process.push_back(InsertProcess { element_id, node_index, node_rect, depth});
+        for &element_id in &removed {
+            self.data.erase(self.elm_rects[element_id].data_id);
+            self.elm_rects.erase(element_id);
+        }
 
-        while let Some(node_data) = to_process.pop_front() {
+        removed.len()
+    }
 
-            let leaves = self.find_leaves(node_index, node_rect, self.elm_rects[element_id].rect, depth);
+    pub fn get(&self, element_id: ElementId) -> Option<&T> {
+        if !self.elm_rects.is_live(element_id) {
+            return None;
+        }
 
-            for leaf in &leaves {
+        Some(&self.data[self.elm_rects[element_id].data_id])
+    }
 
-                let current_first_child = self.nodes[leaf.node_index].first_child;
+    /// Mutable access to the element. To move it use ['update()'].
+    /// Call ['refresh_aggregate()'] after changing it, when the tree has an aggregate
+    pub fn get_mut(&mut self, element_id: ElementId) -> Option<&mut T> {
+        if !self.elm_rects.is_live(element_id) {
+            return None;
+        }
 
-                // insert into leaf, using current leaf first child as this ones next
-                // setting leaf first child to this
-                let elm_node_index = self.element_nodes.insert(ElmRectNode {
-                    next: current_first_child,
-                    elm_id: element_id
-                });
+        Some(&mut self.data[self.elm_rects[element_id].data_id])
+    }
 
+    pub fn rect(&self, element_id: ElementId) -> Option<Rect> {
+        if !self.elm_rects.is_live(element_id) {
+            return None;
+        }
 
-                // first child is set to a node that was just created
-                assert!(current_first_child != elm_node_index);
+        Some(self.elm_rects[element_id].rect)
+    }
 
-                self.nodes[leaf.node_index].first_child = elm_node_index;
-                self.nodes[leaf.node_index].count += 1;
+    pub fn contains(&self, element_id: ElementId) -> bool {
+        self.elm_rects.is_live(element_id)
+    }
 
+    /// Number of elements in the tree
+    pub fn len(&self) -> usize {
+        self.elm_rects.elements_count() as usize
+    }
 
-                // Split node if too big and not too far down
-                if self.nodes[leaf.node_index].count >= self.elements_per_node && leaf.depth < self.max_depth  {
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 
+    /// Undo growth of the root from ['set_auto_grow()'], for as long as there are no elements
+    /// only outside the quad the old root became
+    pub fn shrink_to_fit(&mut self) {
 
-                    // transfer node children to tmp list
-                    // TODO: Maybe allocate a tmp buffer for this on self??
-                    let mut element_list = FreeList::<i32>::new();
+        while let Some(&quad) = self.grown_from.last() {
 
-                    while self.nodes[leaf.node_index].first_child != -1 {
+            if self.nodes[0].is_leaf() {
+                break;
+            }
 
-                        let index = self.nodes[leaf.node_index].first_child;
-                        let next_index = self.element_nodes[index].next;
-                        let elm_rect_id =  self.element_nodes[index].elm_id;
+            let first_child = self.nodes[0].first_child;
 
-                        // store element so we can insert into children
-                        element_list.insert(elm_rect_id);
+            // elements in the other quads that also are in the old root are kept there
+            let old_root_rect = self.root_rect.location_quads()[quad];
 
-                        // iterate
-                        // Maybe use local variable??
-                        assert!(self.nodes[leaf.node_index].first_child != next_index);
-                        self.nodes[leaf.node_index].first_child = next_index;
-                        self.element_nodes.erase(index);
+            let siblings_empty = (0..4)
+                .filter(|&i| i != quad)
+                .all(|i| self.subtree_within(first_child + i as i32, old_root_rect));
 
-                        assert!(self.nodes[leaf.node_index].first_child != index);
-                    }
+            if !siblings_empty {
+                break;
+            }
 
-                    assert!(self.nodes[leaf.node_index].first_child == -1);
+            for i in (0..4).filter(|&i| i != quad) {
+                self.free_subtree(first_child + i as i32);
+            }
+
+            // old root takes the place of the root again
+            let old_root = first_child + quad as i32;
 
+            if self.nodes[old_root].is_leaf() {
+                self.notify_leave_all(old_root);
+            }
 
+            self.nodes[0].first_child = self.nodes[old_root].first_child;
+            self.nodes[0].count = self.nodes[old_root].count;
 
-                    // allocate 4 children
-                    let index = self.nodes.insert(Node::leaf());
-                    let index2 = self.nodes.insert(Node::leaf());
-                    let index3 = self.nodes.insert(Node::leaf());
-                    let index4 = self.nodes.insert(Node::leaf());
+            self.nodes.free_block(first_child);
+
+            if self.nodes[0].is_leaf() {
+                self.notify(|o| o.on_merge(0));
+                self.notify_enter_all(0);
+            }
+            else {
+                self.notify(|o| o.on_nodes_moved(&[(old_root, 0)]));
+            }
+
+            self.root_rect = self.root_rect.location_quads()[quad];
+            self.max_depth -= 1;
+            self.grown_from.pop();
+        }
+
+        self.recompute_aggregates();
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Leaf {
+    pub node_index: i32,
+    pub depth: i32,
+    pub rect: Rect,
+}
+
+struct InsertProcess {
+    element_id: i32,
+    node_index: i32,
+    node_rect: Rect,
+    depth: i32
+}
+// Private functions
+impl<T, A: Aggregate<T>> QuadTree<T, A> {
+
+    fn insert_with_margin(&mut self, element: T, element_rect: Rect, margin: i32) -> ElementId {
+
+        // grow first, growing links existing elements into the new quads
+        self.fit_root(element_rect);
+
+        let data_id = self.data.insert(element);
+
+        let element_id = self.elm_rects.insert(ElmRect {
+            data_id,
+            rect: element_rect,
+            fat: element_rect.expand(margin),
+            margin,
+        });
+
+        self.node_insert(element_id, 0, self.root_rect, 0);
+        self.aggregate_linked(element_id);
+
+        element_id
+    }
+
+    fn remove_element(&mut self, element_id: ElementId) -> T {
+        self.unlink(element_id);
+        self.aggregate_unlinked(self.elm_rects[element_id].rect);
+
+        // also data? but that could be slow???
+        let data = self.data.take(self.elm_rects[element_id].data_id);
+        self.elm_rects.erase(element_id);
+
+        data
+    }
+
+
+    // Remove element from all leaves it is in
+    fn unlink(&mut self, element_id: ElementId) {
+        self.maintain_idle = false;
+
+        let elm = &self.elm_rects[element_id];
+        let leaves = self.find_leaves(0, self.root_rect, elm.fat, 0);
+
+        for &leaf in &leaves {
+            let leaf_index = leaf.node_index;
+            let leaf_node = &mut self.nodes[leaf_index];
+
+            let mut element_index = leaf_node.first_child;
+            let mut prev_index = -1;
+
+            // only walk until we find element
+            while element_index != -1 && self.element_nodes[element_index].elm_id != element_id {
+                prev_index = element_index;
+                element_index = self.element_nodes[element_index].next;
+
+            }
+
+            if element_index != -1 {  // elment found at element_index
+                assert_eq!(self.element_nodes[element_index].elm_id, element_id);
+
+
+                let next_index = self.element_nodes[element_index].next;
+
+                if prev_index == -1 { // found element is first child, so se node to next
+                    self.nodes[leaf_index].first_child = next_index;
+                } else {
+                    // in middle set
+                    self.element_nodes[prev_index].next = next_index;
+                }
+
+                //println!("{:?}", (prev_index, element_index, next_index));
+                self.element_nodes.erase(element_index);
+                self.nodes[leaf_index].count -= 1;
+                self.notify(|o| o.on_element_leave_leaf(element_id, leaf_index));
+            }
+        }
+    }
+
+
+    // Remove all elements intersecting rect from all leaves they are in, returns their ids.
+    // Only walks the leaves rect intersects, elements that also are outside rect
+    // are afterwards removed from the remaining leaves one by one
+    fn unlink_in(&mut self, rect: Rect) -> Vec::<ElementId> {
+
+        self.maintain_idle = false;
+        self.ensure_query_tmp_buffer_size();
+
+        let mut removed = vec![];
+
+        let leaves = self.find_leaves(0, self.root_rect, rect, 0);
+
+        for &leaf in &leaves {
+
+            let mut element_index = self.nodes[leaf.node_index].first_child;
+            let mut prev_index = -1;
+
+            while element_index != -1 {
+                let element_id = self.element_nodes[element_index].elm_id;
+                let next_index = self.element_nodes[element_index].next;
+
+                if !rect.intersect(self.elm_rects[element_id].rect) {
+                    prev_index = element_index;
+                    element_index = next_index;
+                    continue;
+                }
+
+                if prev_index == -1 {
+                    self.nodes[leaf.node_index].first_child = next_index;
+                } else {
+                    self.element_nodes[prev_index].next = next_index;
+                }
+
+                self.element_nodes.erase(element_index);
+                self.nodes[leaf.node_index].count -= 1;
+                self.notify(|o| o.on_element_leave_leaf(element_id, leaf.node_index));
+
+                if !self.query_tmp_buffer[element_id as usize] {
+                    self.query_tmp_buffer[element_id as usize] = true;
+                    removed.push(element_id);
+                }
+
+                element_index = next_index;
+            }
+        }
+
+        for &element_id in &removed {
+            self.query_tmp_buffer[element_id as usize] = false;
+
+            if !rect.contains(self.elm_rects[element_id].fat) {
+                self.unlink(element_id);
+            }
+        }
+
+        self.aggregate_unlinked_in(rect, &removed);
+
+        removed
+    }
+
+
+    fn ensure_query_tmp_buffer_size(&mut self) {
+        // make sure our query tmp buffer is big enough
+        if self.query_tmp_buffer.len() < self.elm_rects.data_len() as usize {
+            for _ in 0..(self.elm_rects.data_len() as usize - self.query_tmp_buffer.len()) {
+                self.query_tmp_buffer.push(false);
+            }
+        }
+    }
+
+
+    // Grow the root to contain rect, if auto grow is on
+    fn fit_root(&mut self, rect: Rect) {
+        if self.auto_grow && !self.root_rect.contains(rect) {
+
+            // check before changing anything
+            assert!(self.can_grow_to(rect), "Cannot grow root {:?} to contain {:?}, use try_insert or try_update", self.root_rect, rect);
+
+            while !self.root_rect.contains(rect) {
+                self.grow(rect);
+            }
+
+            // anchors on the edge of the old root can belong to the new quads
+            self.recompute_aggregates();
+        }
+    }
+
+
+    // True if doubling the root enough times makes it contain rect
+    fn can_grow_to(&self, rect: Rect) -> bool {
+        let mut root = self.root_rect;
+
+        // at most 32 times before overflowing
+        while !root.contains(rect) {
+            match root.grown_towards(rect) {
+                Some(grown) => root = grown,
+                None => return false,
+            }
+        }
+
+        true
+    }
+
+
+    // Top left corner of where rect overlaps the root
+    fn anchor(&self, rect: Rect) -> Point {
+        Point::new(i32::max(rect.left, self.root_rect.left), i32::min(rect.top, self.root_rect.top))
+    }
+
+
+    fn check_element(&self, element_id: ElementId) -> Result<(), QuadTreeError> {
+        if self.elm_rects.is_live(element_id) {
+            Ok(())
+        }
+        else {
+            Err(QuadTreeError::InvalidElement(element_id))
+        }
+    }
+
+
+    fn check_rect(&self, rect: Rect) -> Result<(), QuadTreeError> {
+        if rect.is_empty() {
+            return Err(QuadTreeError::InvalidRect(rect));
+        }
+
+        if !self.auto_grow && !self.root_rect.intersect(rect) {
+            return Err(QuadTreeError::OutOfBounds(rect));
+        }
+
+        if self.auto_grow && !self.can_grow_to(rect) {
+            return Err(QuadTreeError::CannotGrow(rect));
+        }
+
+        Ok(())
+    }
+
+    // Double the size of the root towards rect. The old root becomes
+    // the quad of the new root that is away from rect
+    fn grow(&mut self, rect: Rect) {
+
+        self.maintain_idle = false;
+
+        let old = self.root_rect;
+
+        let grow_left = rect.left < old.left;
+        let grow_down = rect.bottom < old.bottom;
+
+        // fit_root checked it can grow
+        self.root_rect = old.grown_towards(rect).expect("Root cannot grow");
+
+        // TL, TR, BL, BR
+        let quad = (!grow_down as usize) * 2 + grow_left as usize;
+
+        let first_child = self.nodes.alloc_block([Node::leaf(), Node::leaf(), Node::leaf(), Node::leaf()]);
+
+        // move old root into its quad
+        let old_root = first_child + quad as i32;
+
+        if self.nodes[0].is_leaf() {
+            self.notify_leave_all(0);
+        }
+        else {
+            self.notify(|o| o.on_nodes_moved(&[(0, old_root)]));
+        }
+
+        self.nodes[old_root].first_child = self.nodes[0].first_child;
+        self.nodes[old_root].count = self.nodes[0].count;
+
+        self.nodes[0].first_child = first_child;
+        self.nodes[0].count = -1;
+
+        let root_rect = self.root_rect;
+        self.notify(|o| o.on_split(0, root_rect));
+
+        if self.nodes[old_root].is_leaf() {
+            self.notify_enter_all(old_root);
+        }
+
+        // Elements on the edge of the old root also touch the new quads next to it.
+        // Link them there too, so every element is in all leaves it intersects
+        let locations = self.root_rect.location_quads();
+        let on_edge: Vec::<ElementId> = self.elm_rects.iter()
+            .filter(|(_, elm)| elm.fat.intersect(old))
+            .map(|(element_id, _)| element_id)
+            .collect();
+
+        for element_id in on_edge {
+            let element_rect = self.elm_rects[element_id].fat;
+
+            for (i, &location) in locations.iter().enumerate() {
+                if i != quad && location.intersect(element_rect) {
+                    self.node_insert(element_id, first_child + i as i32, location, 1);
+                }
+            }
+        }
+
+        // the old root keeps its depth limit
+        self.max_depth += 1;
+        self.grown_from.push(quad);
+    }
+
+
+    // Make branch, where all children are empty leaves, into an empty leaf
+    pub(crate) fn merge_children(&mut self, node_id: i32) {
+        self.maintain_idle = false;
+
+        let branch = &mut self.nodes[node_id];
+        let first_child = branch.first_child;
+
+        // -1 for no children
+        // count = 0 for leaf
+        branch.first_child = -1;
+        branch.count = 0;
+
+        self.nodes.free_block(first_child);
+
+        self.notify(|o| o.on_merge(node_id));
+    }
+
+
+    // True if all elements in leaves below node_index intersects rect
+    fn subtree_within(&self, node_index: i32, rect: Rect) -> bool {
+        let node = &self.nodes[node_index];
+
+        if node.is_leaf() {
+            let mut element_index = node.first_child;
+
+            while element_index != -1 {
+                let elm_node = &self.element_nodes[element_index];
+
+                if !rect.intersect(self.elm_rects[elm_node.elm_id].rect) {
+                    return false;
+                }
+                element_index = elm_node.next;
+            }
+
+            return true;
+        }
+
+        (0..4).all(|i| self.subtree_within(node.first_child + i, rect))
+    }
+
+
+    // Erase all nodes below node_index, and the element nodes in its leaves.
+    // Does not touch the elements
+    fn free_subtree(&mut self, node_index: i32) {
+
+        self.maintain_idle = false;
+
+        if self.nodes[node_index].is_leaf() {
+            self.notify_leave_all(node_index);
+
+            while self.nodes[node_index].first_child != -1 {
+                let element_index = self.nodes[node_index].first_child;
+
+                self.nodes[node_index].first_child = self.element_nodes[element_index].next;
+                self.element_nodes.erase(element_index);
+            }
+
+            self.nodes[node_index].count = 0;
+            return;
+        }
+
+        let first_child = self.nodes[node_index].first_child;
+
+        for i in 0..4 {
+            self.free_subtree(first_child + i);
+        }
+
+        self.nodes[node_index].first_child = -1;
+        self.nodes[node_index].count = 0;
+
+        self.nodes.free_block(first_child);
+
+        self.notify(|o| o.on_merge(node_index));
+    }
+
+
+    fn find_leaves(&self, node_index: i32, node_rect: Rect, search_rect: Rect, depth: i32) -> Vec::<Leaf> {
+        let mut res = vec![];
+
+
+        // start at input node, at branches see which overlaps with rect, process those too
+        // return vec of nodes that rect overlaps
+        let mut to_process = VecDeque::new();
+
+        //0 is root
+        to_process.push_back(FindLeaves{ node_id: node_index, rect: node_rect, depth});
+
+
+        while let Some(node_data) = to_process.pop_front() {
+
+
+            let node = &self.nodes[node_data.node_id];
+
+            // if node is a leaf, push to result
+            if self.nodes[node_data.node_id].count != -1 {
+                assert!(self.nodes[node_data.node_id].count != -1);
+
+                res.push(Leaf{ node_index: node_data.node_id, depth: node_data.depth, rect: node_data.rect });
+            }
+            else {
+
+                let locations = node_data.rect.location_quads();
+
+                for (i, &location) in locations.iter().enumerate() {
+                    if location.intersect(search_rect) {
+                        to_process.push_back(FindLeaves { node_id: node.first_child + i as i32,
+                                                          rect: location,
+                                                          depth: node_data.depth + 1 });
+
+                    }
+                }
+            }
+
+        }
+
+
+        res
+    }
+
+
+
+
+    // insert element_id(index into self.elm_rects) into the node with the given index
+    fn node_insert(&mut self, element_id: i32, node_index: i32, node_rect: Rect, depth: i32) {
+
+        self.maintain_idle = false;
+
+        let mut to_process = VecDeque::new();
+
+        to_process.push_back(InsertProcess { element_id, node_index, node_rect, depth});
+
+        while let Some(node_data) = to_process.pop_front() {
+
+            let element_rect = self.elm_rects[node_data.element_id].fat;
+            let leaves = self.find_leaves(node_data.node_index, node_data.node_rect, element_rect, node_data.depth);
+
+            for leaf in &leaves {
+
+                // insert into leaf, using current leaf first child as this ones next
+                // setting leaf first child to this
+                ElmRectNode::insert(node_data.element_id, &mut self.nodes[leaf.node_index], &mut self.element_nodes);
+                self.notify(|o| o.on_element_enter_leaf(node_data.element_id, leaf.node_index));
+
+
+                // Split node if too big and not too far down
+                if self.nodes[leaf.node_index].count >= self.elements_per_node && leaf.depth < self.max_depth  {
+
+
+                    // transfer node children to tmp list
+                    // TODO: Maybe allocate a tmp buffer for this on self??
+                    let mut element_list = FreeList::<i32>::new();
+
+                    while self.nodes[leaf.node_index].first_child != -1 {
+
+                        let index = self.nodes[leaf.node_index].first_child;
+                        let next_index = self.element_nodes[index].next;
+                        let elm_rect_id =  self.element_nodes[index].elm_id;
+
+                        // store element so we can insert into children
+                        element_list.insert(elm_rect_id);
+                        self.notify(|o| o.on_element_leave_leaf(elm_rect_id, leaf.node_index));
+
+                        // iterate
+                        // Maybe use local variable??
+                        assert!(self.nodes[leaf.node_index].first_child != next_index);
+                        self.nodes[leaf.node_index].first_child = next_index;
+                        self.element_nodes.erase(index);
+
+                        assert!(self.nodes[leaf.node_index].first_child != index);
+                    }
+
+                    assert!(self.nodes[leaf.node_index].first_child == -1);
+
+
+
+                    // allocate 4 children
+                    let index = self.nodes.alloc_block([Node::leaf(), Node::leaf(), Node::leaf(), Node::leaf()]);
+
+
+
+                    // make leaf into a node
+                    self.nodes[leaf.node_index].first_child = index;
+                    self.nodes[leaf.node_index].count = -1;
+
+                    self.split_log.push((leaf.node_index, leaf.rect));
+                    self.notify(|o| o.on_split(leaf.node_index, leaf.rect));
+
+
+                    // push current children to be processed (inserted into leaves)
+                    for i in 0..element_list.data_len() {
+                        to_process.push_back(InsertProcess {
+                            element_id: element_list[i],
+                            node_index: leaf.node_index,
+                            node_rect: leaf.rect,
+                            depth: leaf.depth });
+
+                        //self.node_insert(element_list[i], node_index, leaf.rect, leaf.depth + 1);
+                    }
+                }
+            }
+        }
+
+        //println!("Insert exit");
+    }
+
+
+    fn print(&self) -> String {
+        self.print_node(0, 0)
+    }
+
+
+    fn print_node(&self, node_index: i32, indent: usize) -> String {
+
+        if self.nodes[node_index].count >= 0 {
+            // leaf
+
+            if self.nodes[node_index].count > 0 {
+                let mut child_index = self.nodes[node_index].first_child;
+
+                let mut res = "".to_string();
+                while child_index != -1 {
+                    let elm_node = &self.element_nodes[child_index];
+                    res += &format!(" e({}), idx: {:?} | ", elm_node.elm_id, child_index);
+                    child_index = elm_node.next;
+                }
+
+
+                format!("\n{:indent$}-{}", "", res, indent=indent)
+            }
+            else {
+                format!("\n{:indent$}-Empty", "", indent=indent )
+            }
+        }
+        else {
+            // branch
+            let first_index = self.nodes[node_index].first_child;
+
+            let mut res = format!("\n{:indent$}","", indent=indent);
+            res += &format!("Branch({})", node_index);
+
+
+            res += &self.print_node(first_index, indent + 4);
+            res += &self.print_node(first_index + 1, indent + 4);
+            res += &self.print_node(first_index + 2, indent + 4);
+            res += &self.print_node(first_index + 3, indent + 4);
+
+            res
+
+        }
+
+    }
+
+}
+
+
+impl<T, A: Aggregate<T>> fmt::Display for QuadTree<T, A> {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.print())
+    }
+}
+
+impl<T, A: Aggregate<T>> fmt::Debug for QuadTree<T, A> {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+
+}
+
+
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use std::ops::ControlFlow;
+
+    #[test]
+    fn node_locations_all() {
+
+        let node_rect = Rect::from_points(Point {x: -128, y: -128}, Point { x: 128, y: 128} );
+
+        let element_rect = Rect::from_points(Point {x: -10, y: -10}, Point { x: 20, y: 20} );
+
+        let locations = Rect::element_quad_locations(node_rect, element_rect);
+
+        //println!("{:?}", locations);
+
+        assert!(locations[0] && locations[1] && locations[2] && locations[3]);
+
+    }
+
+
+
+    #[test]
+    fn node_locations_tl() {
+
+        let node_rect = Rect::from_points(Point {x: -128, y: -128}, Point { x: 128, y: 128} );
+
+        let element_rect = Rect::from_points(Point {x: -10, y: 10}, Point { x: -20, y: 20} );
+
+        let locations = Rect::element_quad_locations(node_rect, element_rect);
+
+        //println!("{:?}", locations);
+
+        assert!(locations[0] && !locations[1] && !locations[2] && !locations[3]);
+
+    }
+
+
+    #[test]
+    fn node_locations_tr() {
+
+        let node_rect = Rect::from_points(Point {x: -128, y: -128}, Point { x: 128, y: 128} );
+
+        let element_rect = Rect::from_points(Point {x: 10, y: 10}, Point { x: 20, y: 20} );
+
+        let locations = Rect::element_quad_locations(node_rect, element_rect);
+
+        //println!("{:?}", locations);
+
+        assert!(!locations[0] && locations[1] && !locations[2] && !locations[3]);
+    }
+
+
+    #[test]
+    fn node_locations_bl() {
+
+        let node_rect = Rect::from_points(Point {x: -128, y: -128}, Point { x: 128, y: 128} );
+
+        let element_rect = Rect::from_points(Point {x: -10, y: -10}, Point { x: -20, y: -20} );
+
+        let locations = Rect::element_quad_locations(node_rect, element_rect);
+
+        //println!("{:?}", locations);
+
+        assert!(!locations[0] && !locations[1] && locations[2] && !locations[3]);
+    }
+
+
+
+    #[test]
+    fn node_locations_br() {
+
+        let node_rect = Rect::from_points(Point {x: -128, y: -128}, Point { x: 128, y: 128} );
+
+        let element_rect = Rect::from_points(Point {x: 10, y: -10}, Point { x: 20, y: -20} );
+
+        let locations = Rect::element_quad_locations(node_rect, element_rect);
+
+        //println!("{:?}", locations);
+
+        assert!(!locations[0] && !locations[1] && !locations[2] && locations[3]);
+    }
+
+    #[test]
+    fn insert_2_elm() {
+
+
+
+        let rect = Rect::from_points(Point {x: -128, y: -128}, Point { x: 128, y: 128} );
+
+        let mut qt = QuadTree::<f32>::new(rect);
+
+        qt.set_elements_per_node(2);
+
+        let elm1_id = 1.0;
+        let elm1_rect = Rect::from_points(Point {x: 10, y: 10}, Point { x: 20, y: 20} );
+        qt.insert(elm1_id, elm1_rect);
+
+        let elm2_id = 2.0;
+        let elm2_rect = Rect::from_points(Point {x: -10, y: -10}, Point { x: -20, y: -20} );
+        qt.insert(elm2_id, elm2_rect);
+
+
+
+        let elm2_id = 3.0;
+        let elm2_rect = Rect::from_points(Point {x: 0, y: 0}, Point { x: 5, y: 5} );
+        qt.insert(elm2_id, elm2_rect);
+
+
+        let mut res = vec![];
+
+        qt.query_p(Point::new(15,15), -1, &mut res);
+
+
+        assert_eq!(res.len(), 1);
+        vec_compare(&res, vec![1.0]);
+
+
+        res.clear();
+
+        qt.query_p(Point::new(15,15), -1, &mut res);
+        assert_eq!(res.len(), 1);
+        vec_compare(&res, vec![1.0]);
+
+    }
+
+
+      #[test]
+    fn insert_clear() {
+
+        let rect = Rect::from_points(Point {x: -128, y: -128}, Point { x: 128, y: 128} );
+
+        let mut qt = QuadTree::new(rect);
+
+        qt.set_elements_per_node(2);
+
+
+
+        let elm1 = 1;
+        let elm1_rect = Rect::from_points(Point {x: 10, y: 10}, Point { x: 20, y: 20} );
+        qt.insert(elm1, elm1_rect);
+
+        let elm2 = 2;
+        let elm2_rect = Rect::from_points(Point {x: -10, y: -10}, Point { x: -20, y: -20} );
+        qt.insert(elm2, elm2_rect);
+
+        let elm3 = 3;
+        let elm3_rect = Rect::from_points(Point {x: 0, y: 0}, Point { x: 5, y: 5} );
+        qt.insert(elm3, elm3_rect);
+
+
+        qt.clear();
+
+        let elm1 = 1;
+        let elm1_rect = Rect::from_points(Point {x: 10, y: 10}, Point { x: 20, y: 20} );
+        qt.insert(elm1, elm1_rect);
+
+        let elm2 = 2;
+        let elm2_rect = Rect::from_points(Point {x: 12, y: 12}, Point { x: 20, y: 20} );
+        qt.insert(elm2, elm2_rect);
+
+        let elm3 = 3;
+        let elm3_rect = Rect::from_points(Point {x: 0, y: 0}, Point { x: 5, y: 5} );
+        qt.insert(elm3, elm3_rect);
+
+
+        let mut res = vec![];
+        qt.query_p(Point::new(15,15), -1, &mut res);
+
+        vec_compare(&res, vec![1, 2]);
+
+    }
+
+
+
+    #[test]
+    #[should_panic(expected = "Cannot grow root")]
+    fn auto_grow_past_i32_panics() {
+
+        let mut qt = QuadTree::new(Rect::new(0, 64, 64, 64));
+        qt.set_auto_grow(true);
+
+        qt.insert(1, Rect::new(i32::MAX - 10, i32::MAX - 10, 5, 5));
+    }
+
+
+    // Every edge fits in i32 after growing left, but the width would be 2^31
+    #[test]
+    #[should_panic(expected = "Cannot grow root")]
+    fn auto_grow_past_i32_width_panics() {
+
+        let mut qt = QuadTree::new(Rect::from_points(Point::new(0, -(1 << 30)), Point::new(1 << 30, 0)));
+        qt.set_auto_grow(true);
+
+        qt.insert(1, Rect::new(-10, -10, 1, 1));
+    }
+
+
+    #[test]
+    fn try_insert_rejects_rects_root_cannot_grow_to() {
+
+        let mut qt = QuadTree::new(Rect::new(0, 64, 64, 64));
+        qt.set_auto_grow(true);
+
+        let id = qt.insert(1, Rect::new(10, 10, 1, 1));
+
+        let huge = Rect::new(i32::MAX - 10, i32::MAX - 10, 5, 5);
+        assert_eq!(qt.try_insert(2, huge), Err(QuadTreeError::CannotGrow(huge)));
+        assert_eq!(qt.try_update(id, huge), Err(QuadTreeError::CannotGrow(huge)));
+
+        let low = Rect::new(i32::MIN, i32::MIN + 5, 5, 5);
+        assert_eq!(qt.try_insert(2, low), Err(QuadTreeError::CannotGrow(low)));
+
+        // nothing changed
+        assert_eq!(qt.root_rect(), Rect::new(0, 64, 64, 64));
+        assert_eq!(qt.len(), 1);
+        assert_eq!(qt.rect(id), Some(Rect::new(10, 10, 1, 1)));
+
+        // far, but reachable
+        assert!(qt.try_insert(3, Rect::new(1 << 20, 1 << 20, 1, 1)).is_ok());
+
+        // a root without area can not grow
+        let mut flat = QuadTree::new(Rect::new(0, 0, 64, 0));
+        flat.set_auto_grow(true);
+        let outside = Rect::new(10, 10, 1, 1);
+        assert_eq!(flat.try_insert(1, outside), Err(QuadTreeError::CannotGrow(outside)));
+        assert!(flat.try_insert(2, Rect::new(10, 0, 1, 0)).is_ok());
+    }
+
+
+    #[test]
+    fn try_insert_rejects_growth_past_i32_width() {
+
+        let root = Rect::from_points(Point::new(0, -(1 << 30)), Point::new(1 << 30, 0));
+
+        let mut qt = QuadTree::new(root);
+        qt.set_auto_grow(true);
+
+        let id = qt.insert(1, Rect::new(10, -10, 1, 1));
+
+        // growing left keeps every edge in i32, but not the width
+        let left = Rect::new(-10, -10, 1, 1);
+        assert_eq!(qt.try_insert(2, left), Err(QuadTreeError::CannotGrow(left)));
+        assert_eq!(qt.try_update(id, left), Err(QuadTreeError::CannotGrow(left)));
+
+        assert_eq!(qt.root_rect(), root);
+        assert_eq!(qt.len(), 1);
+        assert_eq!(qt.rect(id), Some(Rect::new(10, -10, 1, 1)));
+
+        // any growth doubles the width, so only rects inside the root are accepted
+        let up = Rect::new(10, 10, 1, 1);
+        assert_eq!(qt.try_insert(3, up), Err(QuadTreeError::CannotGrow(up)));
+        assert!(qt.try_insert(4, Rect::new(20, -20, 1, 1)).is_ok());
+        assert_eq!(qt.len(), 2);
+    }
+
+
+    #[test]
+    fn auto_grow_and_shrink() {
+
+        let rect = Rect::from_points(Point {x: 0, y: 0}, Point { x: 64, y: 64} );
+
+        let mut qt = QuadTree::new(rect);
+
+        qt.set_elements_per_node(2);
+        qt.set_auto_grow(true);
+
+        qt.insert(1, Rect::from_points(Point::new(10, 10), Point::new(20, 20)));
+        qt.insert(2, Rect::from_points(Point::new(30, 30), Point::new(40, 40)));
+
+        let far = qt.insert(3, Rect::from_points(Point::new(-100, 150), Point::new(-90, 160)));
+
+        let root = qt.root_rect();
+        assert!(root.contains(Rect::from_points(Point::new(-100, 0), Point::new(64, 160))));
+
+        let mut res = vec![];
+        qt.query(root, -1, &mut res);
+        vec_compare(&res, vec![1, 2, 3]);
+
+        res.clear();
+        qt.query_p(Point::new(-95, 155), -1, &mut res);
+        vec_compare(&res, vec![3]);
+
+        qt.remove(far);
+        qt.shrink_to_fit();
+
+        let root = qt.root_rect();
+        assert_eq!((root.left, root.bottom, root.right, root.top), (0, 0, 64, 64));
+
+        res.clear();
+        qt.query(root, -1, &mut res);
+        vec_compare(&res, vec![1, 2]);
+    }
+
+
+    #[test]
+    fn try_variants() {
+
+        let rect = Rect::from_points(Point {x: -128, y: -128}, Point { x: 128, y: 128} );
+
+        let mut qt = QuadTree::new(rect);
+
+        let inverted = Rect::new(10, 10, -5, 5);
+        assert_eq!(qt.try_insert(1, inverted), Err(QuadTreeError::InvalidRect(inverted)));
+
+        let outside = Rect::new(200, 200, 5, 5);
+        assert_eq!(qt.try_insert(1, outside), Err(QuadTreeError::OutOfBounds(outside)));
+
+        let id = qt.try_insert(1, Rect::new(10, 10, 5, 5)).unwrap();
+
+        assert_eq!(qt.try_update(id, outside), Err(QuadTreeError::OutOfBounds(outside)));
+        assert_eq!(qt.try_update(id + 1, Rect::new(0, 0, 1, 1)), Err(QuadTreeError::InvalidElement(id + 1)));
+
+        qt.try_update(id, Rect::new(-20, -20, 5, 5)).unwrap();
+
+        let mut res = vec![];
+        qt.query_p(Point::new(12, 8), -1, &mut res);
+        assert!(res.is_empty());
+
+        qt.query_p(Point::new(-18, -22), -1, &mut res);
+        vec_compare(&res, vec![1]);
+
+        assert_eq!(qt.try_remove(id), Ok(1));
+        assert_eq!(qt.try_remove(id), Err(QuadTreeError::InvalidElement(id)));
+    }
+
+
+    #[test]
+    fn element_accessors() {
+
+        let rect = Rect::from_points(Point {x: -128, y: -128}, Point { x: 128, y: 128} );
+
+        let mut qt = QuadTree::new(rect);
+
+        assert!(qt.is_empty());
+
+        let id1 = qt.insert(1, Rect::new(10, 10, 5, 5));
+        let id2 = qt.insert(2, Rect::new(-10, 10, 5, 5));
+
+        assert_eq!(qt.len(), 2);
+        assert_eq!(qt.get(id1), Some(&1));
+        assert_eq!(qt.rect(id2), Some(Rect::new(-10, 10, 5, 5)));
+
+        *qt.get_mut(id2).unwrap() += 10;
+        assert_eq!(qt.get(id2), Some(&12));
+
+        qt.remove(id1);
+
+        assert!(!qt.contains(id1));
+        assert!(qt.contains(id2));
+        assert_eq!(qt.get(id1), None);
+        assert_eq!(qt.rect(id1), None);
+        assert_eq!(qt.get(-1), None);
+        assert_eq!(qt.len(), 1);
+    }
+
+
+    #[test]
+    fn iterate_live_elements() {
 
+        let rect = Rect::from_points(Point {x: -128, y: -128}, Point { x: 128, y: 128} );
 
+        let mut qt = QuadTree::new(rect);
 
-                    // make leaf into a node
-                    self.nodes[leaf.node_index].first_child = index;
-                    self.nodes[leaf.node_index].count = -1;
+        qt.set_elements_per_node(2);
 
+        let ids: Vec::<i32> = (0..6).map(|i| qt.insert(i, Rect::new(i * 10, i * 10, 5, 5))).collect();
 
-                    // push current children to be processed (inserted into leaves)
-                    for i in 0..element_list.data_len() {
-                        to_process.push_back(InsertProcess {
-                            element_id: element_list[i],
-                            node_index,
-                            node_rect: leaf.rect,
-                            depth: leaf.depth + 1 });
+        qt.remove(ids[1]);
+        qt.remove(ids[4]);
 
-                        //self.node_insert(element_list[i], node_index, leaf.rect, leaf.depth + 1);
-                    }
-                }
-            }
+        for (_, _, e) in qt.iter_mut() {
+            *e *= 10;
         }
 
-        //println!("Insert exit");
-    }
+        let items: Vec::<(i32, Rect, i32)> = qt.iter().map(|(id, r, &e)| (id, r, e)).collect();
 
+        assert_eq!(items, vec![(ids[0], Rect::new(0, 0, 5, 5), 0),
+                               (ids[2], Rect::new(20, 20, 5, 5), 20),
+                               (ids[3], Rect::new(30, 30, 5, 5), 30),
+                               (ids[5], Rect::new(50, 50, 5, 5), 50)]);
 
-    fn print(&self) -> String {
-        self.print_node(0, 0)
+        let owned: Vec::<(i32, Rect, i32)> = qt.into_iter().collect();
+        assert_eq!(owned, items);
     }
 
 
-    fn print_node(&self, node_index: i32, indent: usize) -> String {
+    #[test]
+    fn iterate_pairs_by_data_id() {
 
-        if self.nodes[node_index].count >= 0 {
-            // leaf
+        let rect = Rect::from_points(Point {x: -128, y: -128}, Point { x: 128, y: 128} );
 
-            if self.nodes[node_index].count > 0 {
-                let mut child_index = self.nodes[node_index].first_child;
+        let mut qt = QuadTree::new(rect);
 
-                let mut res = "".to_string();
-                while child_index != -1 {
-                    let elm_node = &self.element_nodes[child_index];
-                    res += &format!(" e({}), idx: {:?} | ", elm_node.elm_id, child_index);
-                    child_index = elm_node.next;
-                }
+        let ids: Vec::<i32> = (0..3).map(|i| qt.insert(i, Rect::new(i * 10, i * 10, 5, 5))).collect();
+        qt.remove(ids[1]);
 
+        // element and data ids differ
+        qt.elm_rects[ids[0]].data_id = ids[2];
+        qt.elm_rects[ids[2]].data_id = ids[0];
 
-                return format!("\n{:indent$}-{}", "", res, indent=indent);
-            }
-            else {
-                return format!("\n{:indent$}-Empty", "", indent=indent );
-            }
+        let expected = vec![(ids[0], Rect::new(0, 0, 5, 5), 2), (ids[2], Rect::new(20, 20, 5, 5), 0)];
+
+        let items: Vec::<(i32, Rect, i32)> = qt.iter().map(|(id, r, &e)| (id, r, e)).collect();
+        assert_eq!(items, expected);
+        assert_eq!(qt.get(ids[0]), Some(&2));
+
+        for (_, _, e) in qt.iter_mut() {
+            *e += 100;
         }
-        else {
-            // branch
-            let first_index = self.nodes[node_index].first_child;
+        assert_eq!(qt.get(ids[0]), Some(&102));
 
-            let mut res = format!("\n{:indent$}","", indent=indent);
-            res += &format!("Branch({})", node_index);
+        let owned: Vec::<(i32, Rect, i32)> = qt.into_iter().collect();
+        assert_eq!(owned, vec![(ids[0], Rect::new(0, 0, 5, 5), 102), (ids[2], Rect::new(20, 20, 5, 5), 100)]);
+    }
 
 
-            res += &self.print_node(first_index, indent + 4);
-            res += &self.print_node(first_index + 1, indent + 4);
-            res += &self.print_node(first_index + 2, indent + 4);
-            res += &self.print_node(first_index + 3, indent + 4);
+    #[test]
+    fn drain_and_remove_in() {
+
+        let rect = Rect::from_points(Point {x: -128, y: -128}, Point { x: 128, y: 128} );
+
+        let mut qt = QuadTree::new(rect);
 
-            return res;
+        qt.set_elements_per_node(3);
 
+        for i in 0..10 {
+            qt.insert(i, Rect::new(i * 10 - 50, 0, 5, 5));
         }
 
-    }
+        // spans the region and far outside it
+        let wide = qt.insert(100, Rect::from_points(Point::new(-100, -3), Point::new(100, -1)));
 
-}
+        let region = Rect::from_points(Point::new(-50, -10), Point::new(-1, 10));
 
+        let mut drained: Vec::<(i32, i32)> = qt.drain_in(region).map(|(id, _, e)| (id, e)).collect();
+        drained.sort();
 
-impl<T: std::fmt::Debug> fmt::Display for QuadTree<T> {
+        assert_eq!(drained, vec![(0, 0), (1, 1), (2, 2), (3, 3), (4, 4), (wide, 100)]);
+        assert_eq!(qt.len(), 5);
 
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.print())
-    }
-}
+        let mut res = vec![];
+        qt.query(rect, -1, &mut res);
+        vec_compare(&res, vec![5, 6, 7, 8, 9]);
 
-impl<T: std::fmt::Debug> fmt::Debug for QuadTree<T> {
+        assert_eq!(qt.remove_in(Rect::from_points(Point::new(0, 0), Point::new(25, 25))), 3);
+        assert_eq!(qt.remove_in(region), 0);
 
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self)
+        res.clear();
+        qt.query(rect, -1, &mut res);
+        vec_compare(&res, vec![8, 9]);
     }
 
-}
 
+    #[test]
+    fn retain_once_per_element() {
 
+        let rect = Rect::from_points(Point {x: -128, y: -128}, Point { x: 128, y: 128} );
 
-#[cfg(test)]
-mod test {
+        let mut qt = QuadTree::new(rect);
 
-    use super::*;
+        qt.set_elements_per_node(2);
 
-    #[test]
-    fn node_locations_all() {
+        for i in 0..8 {
+            // every element spans the middle lines of the root
+            qt.insert(i, Rect::from_points(Point::new(-10 - i, -10 - i), Point::new(10 + i, 10 + i)));
+        }
 
-        let node_rect = Rect::from_points(Point {x: -128, y: -128}, Point { x: 128, y: 128} );
+        let mut calls = vec![0; 8];
 
-        let element_rect = Rect::from_points(Point {x: -10, y: -10}, Point { x: 20, y: 20} );
+        let removed = qt.retain(|id, _, e| {
+            calls[id as usize] += 1;
+            *e += 100;
+            *e % 2 == 0
+        });
 
-        let locations = Rect::element_quad_locations(node_rect, element_rect);
+        assert_eq!(removed, 4);
+        assert_eq!(calls, vec![1; 8]);
 
-        //println!("{:?}", locations);
+        let mut res = vec![];
+        qt.query(rect, -1, &mut res);
+        vec_compare(&res, vec![100, 102, 104, 106]);
 
-        assert!(locations[0] && locations[1] && locations[2] && locations[3]);
+        assert_eq!(qt.retain(|_, _, _| false), 4);
+        qt.cleanup();
 
+        assert!(qt.is_empty());
+        assert!(qt.all_leaves().iter().all(|l| qt.nodes[l.node_index].count == 0));
     }
 
 
-
     #[test]
-    fn node_locations_tl() {
+    fn retain_and_cleanup_merges_all_levels() {
 
-        let node_rect = Rect::from_points(Point {x: -128, y: -128}, Point { x: 128, y: 128} );
+        let rect = Rect::from_points(Point {x: 0, y: 0}, Point { x: 256, y: 256} );
 
-        let element_rect = Rect::from_points(Point {x: -10, y: 10}, Point { x: -20, y: 20} );
+        let mut qt = QuadTree::new(rect);
 
-        let locations = Rect::element_quad_locations(node_rect, element_rect);
+        qt.set_elements_per_node(2);
 
-        //println!("{:?}", locations);
+        // deep in the top left corner, so several levels split
+        for i in 0..6 {
+            qt.insert(i, Rect::new(i, 256 - i, 0, 0));
+        }
+        qt.insert(10, Rect::new(200, 50, 1, 1));
 
-        assert!(locations[0] && !locations[1] && !locations[2] && !locations[3]);
+        assert!(qt.all_leaves().iter().any(|l| l.depth > 2));
+
+        // nothing removed, nothing merged
+        let leaves = qt.all_leaves().len();
+        assert_eq!(qt.retain_and_cleanup(|_, _, _| true), 0);
+        assert_eq!(qt.all_leaves().len(), leaves);
 
+        assert_eq!(qt.retain_and_cleanup(|_, _, &mut e| e == 10), 6);
+
+        // one call merged every level that only had empty leaves
+        assert!(qt.all_leaves().iter().all(|l| l.depth == 1));
+        assert_eq!(qt.len(), 1);
+        assert_eq!(qt.count_in(rect), 1);
     }
 
 
     #[test]
-    fn node_locations_tr() {
+    fn query_with_reports_once() {
 
-        let node_rect = Rect::from_points(Point {x: -128, y: -128}, Point { x: 128, y: 128} );
+        let rect = Rect::from_points(Point {x: -128, y: -128}, Point { x: 128, y: 128} );
 
-        let element_rect = Rect::from_points(Point {x: 10, y: 10}, Point { x: 20, y: 20} );
+        let mut qt = QuadTree::new(rect);
 
-        let locations = Rect::element_quad_locations(node_rect, element_rect);
+        qt.set_elements_per_node(2);
 
-        //println!("{:?}", locations);
+        for i in 0..8 {
+            qt.insert(i, Rect::from_points(Point::new(-10 - i, -10 - i), Point::new(10 + i, 10 + i)));
+            qt.insert(i + 10, Rect::new(i * 10, 50, 1, 1));
+        }
 
-        assert!(!locations[0] && locations[1] && !locations[2] && !locations[3]);
+        // touches the middle lines of the root
+        qt.insert(20, Rect::from_points(Point::new(0, 0), Point::new(0, 0)));
+
+        let mut found = vec![];
+        qt.query_with(rect, |_, _, &e| {
+            found.push(e);
+            ControlFlow::Continue(())
+        });
+
+        let mut expected: Vec::<i32> = (0..8).chain(10..18).collect();
+        expected.push(20);
+        vec_compare(&found, expected);
+
+        found.clear();
+        qt.query_with(Rect::from_points(Point::new(-5, -5), Point::new(0, 0)), |_, _, &e| {
+            found.push(e);
+            ControlFlow::Continue(())
+        });
+        vec_compare(&found, vec![0, 1, 2, 3, 4, 5, 6, 7, 20]);
+
+        let mut calls = 0;
+        qt.query_with(rect, |_, _, _| {
+            calls += 1;
+            if calls == 3 { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+        });
+        assert_eq!(calls, 3);
     }
 
 
     #[test]
-    fn node_locations_bl() {
+    fn query_with_after_grow() {
 
-        let node_rect = Rect::from_points(Point {x: -128, y: -128}, Point { x: 128, y: 128} );
+        let rect = Rect::from_points(Point {x: 0, y: 0}, Point { x: 64, y: 64} );
 
-        let element_rect = Rect::from_points(Point {x: -10, y: -10}, Point { x: -20, y: -20} );
+        let mut qt = QuadTree::new(rect);
 
-        let locations = Rect::element_quad_locations(node_rect, element_rect);
+        qt.set_auto_grow(true);
 
-        //println!("{:?}", locations);
+        // on the left edge of the root, which becomes the middle line when growing left
+        let id1 = qt.insert(1, Rect::from_points(Point::new(0, 10), Point::new(5, 20)));
+        let id2 = qt.insert(2, Rect::new(-50, 10, 5, 5));
 
-        assert!(!locations[0] && !locations[1] && locations[2] && !locations[3]);
-    }
+        let mut found = vec![];
+        qt.query_with(Rect::from_points(Point::new(-10, 0), Point::new(10, 30)), |_, _, &e| {
+            found.push(e);
+            ControlFlow::Continue(())
+        });
+        vec_compare(&found, vec![1]);
 
+        qt.remove(id2);
+        qt.shrink_to_fit();
+        assert_eq!(qt.root_rect(), rect);
 
+        // still found after shrinking
+        found.clear();
+        qt.query_with(rect, |_, _, &e| {
+            found.push(e);
+            ControlFlow::Continue(())
+        });
+        vec_compare(&found, vec![1]);
 
-    #[test]
-    fn node_locations_br() {
+        qt.remove(id1);
+        assert_eq!(qt.root_rect(), rect);
+    }
 
-        let node_rect = Rect::from_points(Point {x: -128, y: -128}, Point { x: 128, y: 128} );
 
-        let element_rect = Rect::from_points(Point {x: 10, y: -10}, Point { x: 20, y: -20} );
+    #[test]
+    fn query_non_copy() {
 
-        let locations = Rect::element_quad_locations(node_rect, element_rect);
+        let rect = Rect::from_points(Point {x: -128, y: -128}, Point { x: 128, y: 128} );
 
-        //println!("{:?}", locations);
+        let mut qt = QuadTree::new(rect);
 
-        assert!(!locations[0] && !locations[1] && !locations[2] && locations[3]);
-    }
+        qt.set_elements_per_node(2);
 
-    #[test]
-    fn insert_2_elm() {
+        let a = qt.insert("a".to_string(), Rect::new(10, 10, 5, 5));
+        let b = qt.insert("b".to_string(), Rect::from_points(Point::new(-10, -10), Point::new(10, 10)));
+        qt.insert("c".to_string(), Rect::new(-50, -50, 5, 5));
 
+        let query_r = Rect::from_points(Point::new(0, 0), Point::new(20, 20));
 
+        let mut ids = vec![];
+        qt.query_ids(query_r, -1, &mut ids);
+        ids.sort();
+        assert_eq!(ids, vec![a, b]);
 
-        let rect = Rect::from_points(Point {x: -128, y: -128}, Point { x: 128, y: 128} );
+        let mut refs = vec![];
+        qt.query_refs(query_r, b, &mut refs);
+        assert_eq!(refs, vec![&"a".to_string()]);
 
-        let mut qt = QuadTree::<f32>::new(rect);
+        let mut owned = vec![];
+        qt.query_p(Point::new(10, 8), -1, &mut owned);
+        owned.sort();
+        assert_eq!(owned, vec!["a".to_string(), "b".to_string()]);
+    }
 
-        qt.set_elements_per_node(2);
 
-        let elm1_id = 1.0;
-        let elm1_rect = Rect::from_points(Point {x: 10, y: 10}, Point { x: 20, y: 20} );
-        qt.insert(elm1_id, elm1_rect);
+    #[derive(Clone, Debug, PartialEq)]
+    struct SumMax(i64, i64);
 
-        let elm2_id = 2.0;
-        let elm2_rect = Rect::from_points(Point {x: -10, y: -10}, Point { x: -20, y: -20} );
-        qt.insert(elm2_id, elm2_rect);
+    impl Aggregate<i64> for SumMax {
+        fn identity() -> Self {
+            SumMax(0, i64::MIN)
+        }
 
+        fn combine(&self, other: &Self) -> Self {
+            SumMax(self.0 + other.0, i64::max(self.1, other.1))
+        }
 
+        fn from_element(element: &i64) -> Self {
+            SumMax(*element, *element)
+        }
+    }
 
-        let elm2_id = 3.0;
-        let elm2_rect = Rect::from_points(Point {x: 0, y: 0}, Point { x: 5, y: 5} );
-        qt.insert(elm2_id, elm2_rect);
 
+    #[test]
+    fn aggregate_matches_query() {
 
-        let mut res = vec![];
+        let rect = Rect::from_points(Point {x: 0, y: 0}, Point { x: 64, y: 64} );
 
-        qt.query_p(Point::new(15,15), -1, &mut res);
+        let mut qt = QuadTree::<i64, SumMax>::with_aggregate(rect);
 
+        qt.set_elements_per_node(3);
+        qt.set_auto_grow(true);
 
-        assert_eq!(res.len(), 1);
-        vec_compare(&res, vec![1.0]);
+        // simple lcg, so the test is the same every run
+        let mut seed = 7u32;
+        let mut next = |max: i32| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            ((seed >> 8) % max as u32) as i32
+        };
 
+        let check = |qt: &QuadTree<i64, SumMax>, query_r: Rect| {
+            let mut expected = SumMax::identity();
+            qt.query_with(query_r, |_, _, e| {
+                expected = expected.combine(&SumMax::from_element(e));
+                ControlFlow::Continue(())
+            });
+            assert_eq!(qt.aggregate_in(query_r), expected, "{:?}", query_r);
+        };
 
-        res.clear();
+        let mut ids = vec![];
 
-        qt.query_p(Point::new(15,15), -1, &mut res);
-        assert_eq!(res.len(), 1);
-        vec_compare(&res, vec![1.0]);
+        for i in 0..200 {
+            let (x, y, size) = (next(80) - 8, next(80) - 8, next(12));
+            ids.push(qt.insert(i, Rect::new(x, y, size, size)));
 
-    }
+            if i % 3 == 0 {
+                let id = ids.remove(next(ids.len() as i32) as usize);
+                qt.remove(id);
+            }
 
+            if i % 5 == 0 && !ids.is_empty() {
+                let id = ids[next(ids.len() as i32) as usize];
+                qt.update(id, Rect::new(next(64), next(64), 2, 2));
+            }
+        }
 
-      #[test]
-    fn insert_clear() {
+        let root = qt.root_rect();
+        for _ in 0..200 {
+            let (x, y) = (root.left + next(root.right - root.left), root.bottom + next(root.top - root.bottom));
+            check(&qt, Rect::new(x, y, next(40), next(40)));
+        }
 
-        let rect = Rect::from_points(Point {x: -128, y: -128}, Point { x: 128, y: 128} );
+        check(&qt, root);
+        assert_eq!(qt.aggregate_in(root).0, qt.iter().map(|(_, _, e)| e).sum::<i64>());
 
-        let mut qt = QuadTree::new(rect);
+        qt.remove_in(Rect::new(10, 10, 20, 20));
 
-        qt.set_elements_per_node(2);
+        for &id in &ids {
+            if let Some(e) = qt.get_mut(id) {
+                *e += 1000;
+                qt.refresh_aggregate(id);
+                break;
+            }
+        }
 
+        for _ in 0..100 {
+            check(&qt, Rect::new(next(64), next(64), next(40), next(40)));
+        }
+        check(&qt, qt.root_rect());
+    }
 
 
-        let elm1 = 1;
-        let elm1_rect = Rect::from_points(Point {x: 10, y: 10}, Point { x: 20, y: 20} );
-        qt.insert(elm1, elm1_rect);
+    #[test]
+    fn count_in_matches_query() {
 
-        let elm2 = 2;
-        let elm2_rect = Rect::from_points(Point {x: -10, y: -10}, Point { x: -20, y: -20} );
-        qt.insert(elm2, elm2_rect);
+        let rect = Rect::from_points(Point {x: 0, y: 0}, Point { x: 64, y: 64} );
 
-        let elm3 = 3;
-        let elm3_rect = Rect::from_points(Point {x: 0, y: 0}, Point { x: 5, y: 5} );
-        qt.insert(elm3, elm3_rect);
+        let mut qt = QuadTree::new(rect);
 
+        qt.set_elements_per_node(2);
+        qt.set_auto_grow(true);
 
-        qt.clear();
+        for i in 0..40 {
+            qt.insert(i, Rect::new((i * 7) % 70, (i * 13) % 70, i % 9, i % 5));
+        }
 
-        let elm1 = 1;
-        let elm1_rect = Rect::from_points(Point {x: 10, y: 10}, Point { x: 20, y: 20} );
-        qt.insert(elm1, elm1_rect);
+        // spans every leaf
+        let wide = qt.insert(100, Rect::from_points(Point::new(-5, -5), Point::new(90, 90)));
 
-        let elm2 = 2;
-        let elm2_rect = Rect::from_points(Point {x: 12, y: 12}, Point { x: 20, y: 20} );
-        qt.insert(elm2, elm2_rect);
+        let count = |qt: &QuadTree<i32>, query_r: Rect| {
+            let mut ids = vec![];
+            qt.query_ids(query_r, -1, &mut ids);
+            assert_eq!(qt.count_in(query_r), ids.len(), "{:?}", query_r);
+        };
 
-        let elm3 = 3;
-        let elm3_rect = Rect::from_points(Point {x: 0, y: 0}, Point { x: 5, y: 5} );
-        qt.insert(elm3, elm3_rect);
+        for x in (-10..80).step_by(9) {
+            for y in (-10..80).step_by(11) {
+                count(&qt, Rect::new(x, y, 20, 15));
+                count(&qt, Rect::new(x, y, 0, 0));
+            }
+        }
+
+        assert_eq!(qt.count_in(qt.root_rect()), 41);
 
+        qt.remove(wide);
+        qt.remove_in(Rect::new(20, 20, 10, 10));
+        qt.retain(|_, _, e| *e % 3 != 0);
+        qt.cleanup();
 
-        assert!(false);
+        let mut ids = vec![];
+        qt.query_ids(qt.root_rect(), -1, &mut ids);
+        assert_eq!(qt.count_in(qt.root_rect()), ids.len());
+        assert_eq!(ids.len(), qt.len());
 
+        for x in (-10..80).step_by(9) {
+            count(&qt, Rect::new(x, 30, 25, 25));
+        }
     }
 
 
-
     fn vec_compare<T>(res: &Vec::<T>, expected: Vec::<T>) where T: fmt::Debug + Copy + PartialOrd {
         let mut values = Vec::<T>::new();
 
@@ -634,101 +1884,135 @@ mod test {
         assert_eq!(expected, values);
     }
 
-/*
     #[test]
-    fn insert_remove_1() {
+    fn insert_dynamic_moves_inside_margin() {
 
-        let rect = Rect::new(-128, 128, 256, 256);
-
-        let mut qt = QuadTree::<f32>::new(rect);
+        let rect = Rect::from_points(Point {x: 0, y: 0}, Point { x: 256, y: 256} );
 
-        let elm0_rect = Rect::new(-2, 2, 1, 1);
-        let id0 = qt.insert(0.0, elm0_rect);
+        let mut qt = QuadTree::<usize>::new(rect);
+        qt.set_elements_per_node(4);
 
+        let mut rects = vec![];
+        for i in 0..100 {
+            let r = Rect::new((i as i32 * 37) % 240, (i as i32 * 53) % 240 + 10, 4, 4);
+            qt.insert_dynamic(i, r, 8);
+            rects.push(r);
+        }
 
+        let element_nodes = qt.element_nodes.data_len();
+        let first = qt.nodes[0].first_child;
 
+        // small moves stay inside the fat rects, so nothing is relinked
+        for (i, r) in rects.iter_mut().enumerate() {
+            *r = Rect::new(r.left + 3, r.top - 3, 4, 4);
+            qt.update(i as i32, *r);
+        }
 
+        assert_eq!(qt.element_nodes.data_len(), element_nodes);
+        assert_eq!(qt.nodes[0].first_child, first);
 
-        let elm1_rect = Rect::new(2, 2, 1, 1);
-        let id1 = qt.insert(1.1, elm1_rect);
+        // queries use the real rects, not the fat ones
+        let mut ids = vec![];
+        qt.query_ids(Rect::new(rects[0].left - 2, rects[0].top, 1, 4), -1, &mut ids);
+        assert!(!ids.contains(&0));
 
-        let elm2_rect = Rect::new(2, -2, 1, 1);
-        let id2 = qt.insert(2.2, elm2_rect);
+        // large moves relink
+        for (i, r) in rects.iter_mut().enumerate().step_by(3) {
+            *r = Rect::new(255 - r.left - 4, 255 - r.bottom, 4, 4);
+            qt.update(i as i32, *r);
+        }
 
-        let elm00_rect = Rect::new(-2, -2, 1, 1);
-        let id00 = qt.insert(0.1, elm00_rect);
+        for query_r in [Rect::new(0, 256, 256, 256), Rect::new(20, 200, 90, 70), Rect::new(128, 128, 3, 3)].iter() {
+            let mut ids = vec![];
+            qt.query_ids(*query_r, -1, &mut ids);
+            ids.sort();
 
-        let elm00_rect = Rect::new(-1, 1, 2, 2);
-        let id00 = qt.insert(3.3, elm00_rect);
+            let expected: Vec::<ElementId> = (0..rects.len() as i32)
+                .filter(|&i| rects[i as usize].intersect(*query_r))
+                .collect();
 
-        qt.remove(id00);
+            assert_eq!(ids, expected);
+            assert_eq!(qt.count_in(*query_r), expected.len());
+        }
 
+        assert_eq!(qt.rect(0), Some(rects[0]));
     }
 
 
     #[test]
-    fn cleanup_1() {
+    fn insert_remove_1() {
 
         let rect = Rect::new(-128, 128, 256, 256);
 
         let mut qt = QuadTree::<f32>::new(rect);
 
-        let elm0_rect = Rect::new(5, 5, 1, 1);
-        let id0 = qt.insert(5.0, elm0_rect);
-
-        let elm0_rect = Rect::new(-100, -100, 1, 1);
-        let id1 = qt.insert(7.0, elm0_rect);
-
-        let elm0_rect = Rect::new(3, 3, 1, 1);
-        let id2 = qt.insert(3.0, elm0_rect);
-
-        let elm0_rect = Rect::new(-3, -3, 1, 1);
-        let id3 = qt.insert(-3.0, elm0_rect);
+        qt.insert(0.0, Rect::new(-2, 2, 1, 1));
+        qt.insert(1.1, Rect::new(2, 2, 1, 1));
+        qt.insert(2.2, Rect::new(2, -2, 1, 1));
+        qt.insert(0.1, Rect::new(-2, -2, 1, 1));
 
-        let elm0_rect = Rect::new(-6, -6, 1, 1);
-        let id4 = qt.insert(-6.0, elm0_rect);
+        let id = qt.insert(3.3, Rect::new(-1, 1, 2, 2));
 
-        //println!("\n\ntree:{:?}", qt);
+        let mut res = vec![];
+        qt.query_p(Point::new(0, 0), -1, &mut res);
+        vec_compare(&res, vec![3.3]);
 
-        qt.remove(id0);
+        qt.remove(id);
+        assert_eq!(qt.len(), 4);
 
-        assert_eq!(qt.nodes.active(), 9);
+        res.clear();
+        qt.query_p(Point::new(0, 0), -1, &mut res);
+        assert!(res.is_empty());
 
+        res.clear();
+        qt.query_p(Point::new(2, 2), -1, &mut res);
+        vec_compare(&res, vec![1.1]);
+    }
 
-        // rmeove and cleanup
-        qt.remove(id1);
-        qt.remove(id3);
-        qt.remove(id4);
 
+    #[test]
+    fn cleanup_1() {
 
-        assert_eq!(qt.nodes.active(), 9);
+        let rect = Rect::new(-128, 128, 256, 256);
 
+        let mut qt = QuadTree::<f32>::new(rect);
+        qt.set_elements_per_node(2);
 
-        qt.cleanup();
+        let rects = [Rect::new(5, 5, 1, 1), Rect::new(-100, -100, 1, 1), Rect::new(3, 3, 1, 1),
+                     Rect::new(-3, -3, 1, 1), Rect::new(-6, -6, 1, 1)];
 
-        assert_eq!(qt.nodes.active(), 5);
+        let ids: Vec::<ElementId> = rects.iter().map(|&r| qt.insert(r.left as f32, r)).collect();
 
-        let elm0_rect = Rect::new(-100, -100, 1, 1);
-        let id1 = qt.insert(7.0, elm0_rect);
+        let full = qt.nodes.elements_count();
+        assert!(full > 1);
 
-        let elm0_rect = Rect::new(3, 3, 1, 1);
-        let id2 = qt.insert(3.0, elm0_rect);
+        // removing does not change the tree until cleanup
+        for &id in &ids[1..] {
+            qt.remove(id);
+        }
 
-        let elm0_rect = Rect::new(-3, -3, 1, 1);
-        let id3 = qt.insert(-3.0, elm0_rect);
+        assert_eq!(qt.nodes.elements_count(), full);
 
-        let elm0_rect = Rect::new(-6, -6, 1, 1);
-        let id4 = qt.insert(-6.0, elm0_rect);
+        // cleanup merges one level at a time
+        for _ in 0..qt.max_depth {
+            qt.cleanup();
+        }
 
-        assert_eq!(qt.nodes.active(), 9);
+        assert!(qt.nodes.elements_count() < full);
 
+        let mut res = vec![];
+        qt.query_p(Point::new(5, 5), -1, &mut res);
+        vec_compare(&res, vec![5.0]);
 
+        // the same elements give the same tree again
+        for &r in &rects[1..] {
+            qt.insert(r.left as f32, r);
+        }
 
+        assert_eq!(qt.nodes.elements_count(), full);
     }
 
 
-
-
     #[test]
     fn insert_4_elm() {
 
@@ -740,52 +2024,39 @@ mod test {
 
         // insert 6 top right elements
         for i in 0..6 {
-            let elm_id = i;
-            let elm_rect = Rect::new(10 + i, 10, 1, 1);
-            qt.insert(elm_id, elm_rect);
+            qt.insert(i, Rect::new(10 + i, 10, 1, 1));
         }
 
-
-        // insert 6 top right elements
+        // insert 6 top left elements
         for i in 0..6 {
-            let elm_id = i + 10;
-            let elm_rect = Rect::new(-10 - i, 10, 1, 1);
-            qt.insert(elm_id, elm_rect);
+            qt.insert(i + 10, Rect::new(-10 - i, 10, 1, 1));
         }
 
-
         // insert 6 bottom left elements
         for i in 0..6 {
-            let elm_id = i + 20;
-            let elm_rect = Rect::new(-10 - i, -10, 1, 1);
-            qt.insert(elm_id, elm_rect);
+            qt.insert(i + 20, Rect::new(-10 - i, -10, 1, 1));
         }
 
-
         // insert 6 bottom right elements
         for i in 0..6 {
-            let elm_id = i + 30;
-            let elm_rect = Rect::new(10 + i, -10, 1, 1);
-            qt.insert(elm_id, elm_rect);
+            qt.insert(i + 30, Rect::new(10 + i, -10, 1, 1));
         }
 
+        let mut res = vec![];
+        qt.query_refs(Rect::from_points(Point::new(10, 9), Point::new(16, 10)), -1, &mut res);
+        vec_compare(&res.into_iter().copied().collect(), vec![0, 1, 2, 3, 4, 5]);
 
-        let points = qt.query_p(Point::new(15, 15));
-
-        println!("{}", qt);
-        assert_eq!(points.len(), 6);
-        vec_compare(points, vec![0,1,2,3,4,5]);
-
-
-
-        let points = qt.query_p(Point::new(-15, 15));
-
-        assert_eq!(points.len(), 6);
-
-        vec_compare(points, vec![10,11,12,13,14,15]);
+        let mut res = vec![];
+        qt.query_refs(Rect::from_points(Point::new(-16, 9), Point::new(-9, 10)), -1, &mut res);
+        vec_compare(&res.into_iter().copied().collect(), vec![10, 11, 12, 13, 14, 15]);
 
+        // neighbours share an edge
+        let mut res = vec![];
+        qt.query_p(Point::new(12, -10), -1, &mut res);
+        vec_compare(&res, vec![31, 32]);
     }
 
+
     #[test]
     fn insert_neg_50_50_elm() {
 
@@ -795,27 +2066,27 @@ mod test {
 
         qt.set_elements_per_node(6);
 
-        println!("{:?}", qt.elements_per_node);
+        // points on odd coordinates
         for i in (-51..49).step_by(2) {
             for j in (-51..49).step_by(2) {
-                let rect = Rect::new(i,j,0,0);
-                qt.insert((i,j), rect);
+                qt.insert((i, j), Rect::new(i, j, 0, 0));
             }
         }
 
         let mut res = vec![];
-        qt.query_p(Point::new(15, 15), -1 &mut res);
-
-        assert_eq!(res), 1);
+        qt.query_p(Point::new(15, 15), -1, &mut res);
+        vec_compare(&res, vec![(15, 15)]);
 
-        let points0_0 = qt.query_p(Point::new(0, 0));
-        assert_eq!(points0_0.len(), 16);
+        res.clear();
+        qt.query_p(Point::new(0, 0), -1, &mut res);
+        assert!(res.is_empty());
 
+        // -9 to 9 on both axes
         let search_rect = Rect::from_points(Point {x: -10, y: -10}, Point { x: 10, y: 10} );
-        let points = qt.query(&Query::rect(search_rect));
-
-        assert_eq!(points.len(), 144)
+        let mut ids = vec![];
+        qt.query_ids(search_rect, -1, &mut ids);
 
-}
-*/
+        assert_eq!(ids.len(), 100);
+        assert_eq!(qt.count_in(search_rect), 100);
+    }
 }
@@ -0,0 +1,415 @@
+use crate::*;
+
+
+/// Pointer free quadtree for static point data.
+/// Elements are sorted by the Z-order (Morton) key of their point, so every quad of
+/// the implicit tree is a continuous run of the arrays. The arrays are plain data and can be
+/// written out as is, see keys(), points() and data(), and read back with from_sorted_parts()
+pub struct LinearQuadTree<T> {
+
+    // Morton key of each point, sorted ascending
+    keys: Vec::<u64>,
+
+    // Points, same order as keys
+    points: Vec::<Point>,
+
+    // Actual data, same order as keys. Element id is index into these
+    data: Vec::<T>,
+}
+
+
+// Below this many keys a range is scanned instead of split
+const SCAN_LIMIT: usize = 8;
+
+// Bits belonging to x and y in a key
+const X_MASK: u64 = 0x5555_5555_5555_5555;
+const Y_MASK: u64 = 0xAAAA_AAAA_AAAA_AAAA;
+
+
+/// Z-order key of a point. x uses the even bits and y the odd bits.
+/// Sign bit is flipped so negative coordinates sort before positive
+pub fn morton_code(p: Point) -> u64 {
+    spread_bits(p.x as u32 ^ 0x8000_0000) | (spread_bits(p.y as u32 ^ 0x8000_0000) << 1)
+}
+
+
+// Public interface
+impl<T> LinearQuadTree<T> {
+
+    pub fn new(elements: Vec::<(T, Point)>) -> Self {
+
+        let mut keyed: Vec::<(u64, Point, T)> = elements.into_iter()
+            .map(|(element, point)| (morton_code(point), point, element))
+            .collect();
+
+        keyed.sort_by_key(|e| e.0);
+
+        let mut keys = Vec::with_capacity(keyed.len());
+        let mut points = Vec::with_capacity(keyed.len());
+        let mut data = Vec::with_capacity(keyed.len());
+
+        for (key, point, element) in keyed {
+            keys.push(key);
+            points.push(point);
+            data.push(element);
+        }
+
+        LinearQuadTree {
+            keys,
+            points,
+            data,
+        }
+    }
+
+    /// Tree from arrays as returned by keys(), points() and data(), without sorting them again.
+    /// Checks that they have the same length, that each key is the morton_code of its point and
+    /// that keys are sorted, and returns InvalidParts with the first index where they are not
+    pub fn from_sorted_parts(keys: Vec::<u64>, points: Vec::<Point>, data: Vec::<T>) -> Result<Self, QuadTreeError> {
+
+        let len = usize::min(keys.len(), usize::min(points.len(), data.len()));
+
+        if keys.len() != len || points.len() != len || data.len() != len {
+            return Err(QuadTreeError::InvalidParts(len));
+        }
+
+        for i in 0..len {
+            if keys[i] != morton_code(points[i]) || (i > 0 && keys[i - 1] > keys[i]) {
+                return Err(QuadTreeError::InvalidParts(i));
+            }
+        }
+
+        Ok(LinearQuadTree {
+            keys,
+            points,
+            data,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn keys(&self) -> &[u64] {
+        &self.keys
+    }
+
+    pub fn points(&self) -> &[Point] {
+        &self.points
+    }
+
+    pub fn data(&self) -> &[T] {
+        &self.data
+    }
+}
+
+
+// Public interface for query
+impl<T> LinearQuadTree<T> {
+
+    pub fn query_p_ids(&self, query_p: Point, omit_elm: i32, output: &mut Vec<ElementId>) {
+        self.query_ids(Point::to_rect(query_p), omit_elm, output);
+    }
+
+    /// Ids are indices into keys(), points() and data()
+    pub fn query_ids(&self, query_r: Rect, omit_elm: i32, output: &mut Vec<ElementId>) {
+        self.visit(query_r, omit_elm, &mut |index| output.push(index as ElementId));
+    }
+
+    pub fn query_p_refs<'a>(&'a self, query_p: Point, omit_elm: i32, output: &mut Vec<&'a T>) {
+        self.query_refs(Point::to_rect(query_p), omit_elm, output);
+    }
+
+    pub fn query_refs<'a>(&'a self, query_r: Rect, omit_elm: i32, output: &mut Vec<&'a T>) {
+        self.visit(query_r, omit_elm, &mut |index| output.push(&self.data[index]));
+    }
+}
+
+
+// Cloning queries, see QuadTree in query.rs
+impl<T: Clone> LinearQuadTree<T> {
+
+    pub fn query_p(&self, query_p: Point, omit_elm: i32, output: &mut Vec<T>) {
+        self.query(Point::to_rect(query_p), omit_elm, output);
+    }
+
+    pub fn query(&self, query_r: Rect, omit_elm: i32, output: &mut Vec<T>) {
+        self.visit(query_r, omit_elm, &mut |index| output.push(self.data[index].clone()));
+    }
+}
+
+
+// A query in range_search
+struct RangeQuery {
+    rect: Rect,
+
+    // keys of the bottom left and top right corners
+    box_min: u64,
+    box_max: u64,
+
+    omit_elm: i32,
+}
+
+
+// Private functions
+impl<T> LinearQuadTree<T> {
+
+    fn visit<F: FnMut(usize)>(&self, query_r: Rect, omit_elm: i32, f: &mut F) {
+
+        let query = RangeQuery {
+            rect: query_r,
+            box_min: morton_code(Point::new(query_r.left, query_r.bottom)),
+            box_max: morton_code(Point::new(query_r.right, query_r.top)),
+            omit_elm,
+        };
+
+        self.range_search(0, self.keys.len(), query.box_min, query.box_max, &query, f);
+    }
+
+
+    // Call f with each index in [lo, hi) with key in [zmin, zmax] and point inside the query.
+    // When the middle key is outside the query the range is split into the part below it
+    // ending at LITMAX and the part above it starting at BIGMIN
+    fn range_search<F: FnMut(usize)>(&self, lo: usize, hi: usize, zmin: u64, zmax: u64, query: &RangeQuery, f: &mut F) {
+
+        let lo = lo + self.keys[lo..hi].partition_point(|&k| k < zmin);
+        let hi = lo + self.keys[lo..hi].partition_point(|&k| k <= zmax);
+
+        if lo >= hi {
+            return;
+        }
+
+        if hi - lo <= SCAN_LIMIT {
+            for i in lo..hi {
+                self.report(i, query, f);
+            }
+            return;
+        }
+
+        let mid = lo + (hi - lo) / 2;
+        let z = self.keys[mid];
+
+        // split at the box corners, zmin and zmax are only bounds of the current range
+        if query.rect.contains_point(self.points[mid]) {
+            self.range_search(lo, mid, zmin, zmax, query, f);
+            self.report(mid, query, f);
+            self.range_search(mid + 1, hi, zmin, zmax, query, f);
+        }
+        else {
+            self.range_search(lo, mid, zmin, litmax(z, query.box_min, query.box_max), query, f);
+            self.range_search(mid + 1, hi, bigmin(z, query.box_min, query.box_max), zmax, query, f);
+        }
+    }
+
+    fn report<F: FnMut(usize)>(&self, index: usize, query: &RangeQuery, f: &mut F) {
+        if index as i32 != query.omit_elm && query.rect.contains_point(self.points[index]) {
+            f(index);
+        }
+    }
+}
+
+
+fn spread_bits(v: u32) -> u64 {
+    let mut v = v as u64;
+    v = (v | (v << 16)) & 0x0000_FFFF_0000_FFFF;
+    v = (v | (v << 8)) & 0x00FF_00FF_00FF_00FF;
+    v = (v | (v << 4)) & 0x0F0F_0F0F_0F0F_0F0F;
+    v = (v | (v << 2)) & 0x3333_3333_3333_3333;
+    v = (v | (v << 1)) & 0x5555_5555_5555_5555;
+    v
+}
+
+
+// Lower bits of the same dimension as bit
+fn lower_dim_bits(bit: u32) -> u64 {
+    let dim = if bit & 1 == 0 { X_MASK } else { Y_MASK };
+    dim & ((1u64 << bit) - 1)
+}
+
+// Set bit and clear all lower bits of that dimension
+fn load_1000(v: u64, bit: u32) -> u64 {
+    (v | (1 << bit)) & !lower_dim_bits(bit)
+}
+
+// Clear bit and set all lower bits of that dimension
+fn load_0111(v: u64, bit: u32) -> u64 {
+    (v & !(1 << bit)) | lower_dim_bits(bit)
+}
+
+
+/// Smallest key greater than z that is inside the box given by zmin and zmax.
+/// zmin and zmax are the keys of the bottom left and top right corner of the box.
+/// z must be outside the box and between zmin and zmax
+pub(crate) fn bigmin(z: u64, mut zmin: u64, mut zmax: u64) -> u64 {
+    let mut bigmin = 0;
+
+    for bit in (0..64).rev() {
+        let mask = 1u64 << bit;
+
+        match (z & mask != 0, zmin & mask != 0, zmax & mask != 0) {
+            (false, false, true) => {
+                bigmin = load_1000(zmin, bit);
+                zmax = load_0111(zmax, bit);
+            },
+            (false, true, true) => return zmin,
+            (true, false, false) => return bigmin,
+            (true, false, true) => {
+                zmin = load_1000(zmin, bit);
+            },
+            // (false, false, false), (true, true, true) and the impossible min > max cases
+            _ => {}
+        }
+    }
+
+    bigmin
+}
+
+
+/// Largest key smaller than z that is inside the box given by zmin and zmax.
+/// See bigmin
+pub(crate) fn litmax(z: u64, mut zmin: u64, mut zmax: u64) -> u64 {
+    let mut litmax = 0;
+
+    for bit in (0..64).rev() {
+        let mask = 1u64 << bit;
+
+        match (z & mask != 0, zmin & mask != 0, zmax & mask != 0) {
+            (false, false, true) => {
+                zmax = load_0111(zmax, bit);
+            },
+            (false, true, true) => return litmax,
+            (true, false, false) => return zmax,
+            (true, false, true) => {
+                litmax = load_0111(zmax, bit);
+                zmin = load_1000(zmin, bit);
+            },
+            _ => {}
+        }
+    }
+
+    litmax
+}
+
+
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn sorted(mut res: Vec::<(i32, i32)>) -> Vec::<(i32, i32)> {
+        res.sort();
+        res
+    }
+
+    #[test]
+    fn morton_order() {
+        assert!(morton_code(Point::new(-1, -1)) < morton_code(Point::new(0, 0)));
+        assert!(morton_code(Point::new(0, 0)) < morton_code(Point::new(1, 0)));
+        assert!(morton_code(Point::new(1, 0)) < morton_code(Point::new(0, 1)));
+        assert!(morton_code(Point::new(0, 1)) < morton_code(Point::new(1, 1)));
+    }
+
+
+    #[test]
+    fn bigmin_litmax_in_box() {
+        let query_r = Rect::from_points(Point::new(3, 5), Point::new(5, 10));
+
+        let zmin = morton_code(Point::new(3, 5));
+        let zmax = morton_code(Point::new(5, 10));
+
+        // all keys in the box, sorted
+        let mut in_box = vec![];
+        for x in 3..=5 {
+            for y in 5..=10 {
+                in_box.push(morton_code(Point::new(x, y)));
+            }
+        }
+        in_box.sort();
+
+        for x in 0..16 {
+            for y in 0..16 {
+                let p = Point::new(x, y);
+                let z = morton_code(p);
+                if query_r.contains_point(p) || z < zmin || z > zmax {
+                    continue;
+                }
+
+                let expected_big = *in_box.iter().find(|&&k| k > z).unwrap();
+                let expected_lit = *in_box.iter().rev().find(|&&k| k < z).unwrap();
+
+                assert_eq!(bigmin(z, zmin, zmax), expected_big);
+                assert_eq!(litmax(z, zmin, zmax), expected_lit);
+            }
+        }
+    }
+
+
+    #[test]
+    fn query_matches_scan() {
+
+        let mut elements = vec![];
+        for x in -20..20 {
+            for y in -20..20 {
+                elements.push(((x, y), Point::new(x * 3, y * 2)));
+            }
+        }
+
+        let lqt = LinearQuadTree::new(elements.clone());
+
+        let query_r = Rect::from_points(Point::new(-13, -7), Point::new(22, 9));
+
+        let mut res = vec![];
+        lqt.query(query_r, -1, &mut res);
+
+        let expected: Vec::<(i32, i32)> = elements.iter()
+            .filter(|(_, p)| query_r.contains_point(*p))
+            .map(|&(e, _)| e)
+            .collect();
+
+        assert_eq!(sorted(res), sorted(expected));
+
+        let mut res = vec![];
+        lqt.query_p(Point::new(-6, 4), -1, &mut res);
+        assert_eq!(res, vec![(-2, 2)]);
+    }
+
+
+    #[test]
+    fn from_sorted_parts() {
+
+        let elements: Vec::<(i32, Point)> = (0..50).map(|i| (i, Point::new(i * 7 % 31, i * 3 % 17))).collect();
+        let lqt = LinearQuadTree::new(elements);
+
+        let keys = lqt.keys().to_vec();
+        let points = lqt.points().to_vec();
+        let data = lqt.data().to_vec();
+
+        let copy = LinearQuadTree::from_sorted_parts(keys.clone(), points.clone(), data.clone()).unwrap();
+
+        let query_r = Rect::from_points(Point::new(3, 2), Point::new(20, 12));
+        let mut expected = vec![];
+        let mut res = vec![];
+        lqt.query_ids(query_r, -1, &mut expected);
+        copy.query_ids(query_r, -1, &mut res);
+        assert_eq!(res, expected);
+
+        assert_eq!(LinearQuadTree::from_sorted_parts(keys.clone(), points.clone(), data[1..].to_vec()).err(),
+                   Some(QuadTreeError::InvalidParts(49)));
+
+        let mut unsorted_keys = keys.clone();
+        let mut unsorted_points = points.clone();
+        unsorted_keys.swap(10, 11);
+        unsorted_points.swap(10, 11);
+        assert_eq!(LinearQuadTree::from_sorted_parts(unsorted_keys, unsorted_points, data.clone()).err(),
+                   Some(QuadTreeError::InvalidParts(11)));
+
+        let mut wrong_points = points;
+        wrong_points[5] = Point::new(100, 100);
+        assert_eq!(LinearQuadTree::from_sorted_parts(keys, wrong_points, data).err(),
+                   Some(QuadTreeError::InvalidParts(5)));
+    }
+}
@@ -0,0 +1,377 @@
+use std::time::{Duration, Instant};
+use crate::*;
+
+
+// Steps between checking the clock in maintain
+const STEPS_PER_CHECK: usize = 64;
+
+
+// Incremental version of cleanup and compact. A cursor walks the nodes in index order, one node per step,
+// and merges branches whose children are all empty leaves. A branch that becomes empty by that
+// is merged when the cursor gets to it, in this pass if it comes after its children, otherwise in the next.
+// Branches that are kept move their children to a lower free block, and leaves move their element nodes
+// to lower free slots. At the end of each pass the free slots at the end of the storage are released
+impl<T, A: Aggregate<T>> QuadTree<T, A> {
+
+    /// Do cleanup for at most about budget, continuing where the last call stopped.
+    /// Spread over frames to avoid the pause of ['cleanup()'] on a large tree. Returns the number of branches merged.
+    /// Returns early when a whole pass over the nodes changed nothing, and at once until the tree changes after that.
+    /// Nodes that move change index, and the observer is told as in ['compact()']
+    pub fn maintain(&mut self, budget: Duration) -> usize {
+
+        let start = Instant::now();
+        let mut merged = 0;
+
+        loop {
+            let (steps_merged, done) = self.maintain_run(STEPS_PER_CHECK);
+            merged += steps_merged;
+
+            if done || start.elapsed() >= budget {
+                return merged;
+            }
+        }
+    }
+
+    /// Look at at most n nodes for branches to merge and nodes to move, continuing where the last call stopped.
+    /// Stops early at the end of a pass that changed nothing. Returns the number of branches merged.
+    /// Calling it with the number of nodes, until it returns 0, leaves the tree as repeated calls to ['cleanup()'] would
+    pub fn maintain_steps(&mut self, n: usize) -> usize {
+        self.maintain_run(n).0
+    }
+
+
+    // Branches merged, and true if a pass that changed nothing ended
+    fn maintain_run(&mut self, n: usize) -> (usize, bool) {
+
+        if self.maintain_idle {
+            return (0, true);
+        }
+
+        let mut merged = 0;
+
+        for _ in 0..n {
+
+            if self.maintain_cursor >= self.nodes.data_len() {
+                self.nodes.trim();
+                self.element_nodes.trim();
+                self.maintain_cursor = 0;
+
+                let changed = self.maintain_changed;
+                self.maintain_changed = false;
+
+                if !changed {
+                    self.maintain_idle = true;
+                    return (merged, true);
+                }
+            }
+
+            let node_index = self.maintain_cursor;
+            self.maintain_cursor += 1;
+
+            if !self.nodes.is_live(node_index) {
+                continue;
+            }
+
+            let changed = if self.nodes[node_index].is_leaf() {
+                self.move_element_nodes_down(node_index)
+            }
+            else if self.children_empty(node_index) {
+                self.merge_children(node_index);
+                merged += 1;
+                true
+            }
+            else {
+                self.move_children_down(node_index)
+            };
+
+            self.maintain_changed |= changed;
+        }
+
+        (merged, false)
+    }
+
+
+    // Move the children of branch to a lower free block, true if they moved
+    fn move_children_down(&mut self, branch: i32) -> bool {
+
+        let old = self.nodes[branch].first_child;
+
+        if !self.nodes.can_move_down(old) {
+            return false;
+        }
+
+        // the leaves get new indices, so their elements leave them first
+        for i in 0..4 {
+            if self.nodes[old + i].is_leaf() {
+                self.notify_leave_all(old + i);
+            }
+        }
+
+        let new = self.nodes.move_down(old);
+        self.nodes[branch].first_child = new;
+        self.aggregate_nodes_moved(old, new);
+
+        let moved: Vec::<(i32, i32)> = (0..4).map(|i| (old + i, new + i)).collect();
+        self.notify(|o| o.on_nodes_moved(&moved));
+
+        for i in 0..4 {
+            if self.nodes[new + i].is_leaf() {
+                self.notify_enter_all(new + i);
+            }
+        }
+
+        true
+    }
+
+
+    // Move the element nodes of leaf to lower free slots, true if any moved
+    fn move_element_nodes_down(&mut self, leaf: i32) -> bool {
+
+        let mut moved = false;
+        let mut prev_index = -1;
+        let mut elm_node_index = self.nodes[leaf].first_child;
+
+        while elm_node_index != -1 {
+            let new_index = self.element_nodes.move_down(elm_node_index);
+
+            if new_index != elm_node_index {
+                moved = true;
+
+                if prev_index == -1 {
+                    self.nodes[leaf].first_child = new_index;
+                } else {
+                    self.element_nodes[prev_index].next = new_index;
+                }
+            }
+
+            prev_index = new_index;
+            elm_node_index = self.element_nodes[new_index].next;
+        }
+
+        moved
+    }
+
+
+    fn children_empty(&self, node_index: i32) -> bool {
+        let first_child = self.nodes[node_index].first_child;
+
+        (0..4).all(|i| self.nodes[first_child + i].count == 0)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use std::collections::HashMap;
+
+    fn tree_with_removed() -> QuadTree<i32> {
+
+        let rect = Rect::from_points(Point {x: 0, y: 0}, Point { x: 256, y: 256} );
+
+        let mut qt = QuadTree::new(rect);
+        qt.set_elements_per_node(2);
+
+        let ids: Vec::<ElementId> = (0..200).map(|i| qt.insert(i, Rect::new((i * 37) % 250, (i * 91) % 250 + 5, 1, 1))).collect();
+
+        // keep a few
+        for &id in ids.iter().skip(5) {
+            qt.remove(id);
+        }
+
+        qt
+    }
+
+
+    #[test]
+    fn maintain_steps_matches_cleanup() {
+
+        let mut expected = tree_with_removed();
+        for _ in 0..=expected.max_depth {
+            expected.cleanup();
+        }
+
+        let mut qt = tree_with_removed();
+        let nodes = qt.nodes.data_len();
+
+        // bounded work per call, until a whole pass merges nothing
+        let mut calls = 0;
+        while qt.maintain_steps(16) > 0 || qt.maintain_steps(nodes as usize) > 0 {
+            calls += 1;
+            assert!(calls < 1000);
+        }
+
+        assert!(calls > 1);
+        assert_eq!(qt.nodes.elements_count(), expected.nodes.elements_count());
+        assert!(qt.nodes.data_len() < nodes);
+
+        let mut ids = vec![];
+        qt.query_ids(qt.root_rect(), -1, &mut ids);
+        ids.sort();
+        assert_eq!(ids, vec![0, 1, 2, 3, 4]);
+        assert_eq!(qt.count_in(qt.root_rect()), 5);
+
+        // still works as a tree
+        qt.insert(10, Rect::new(3, 100, 1, 1));
+        assert_eq!(qt.len(), 6);
+    }
+
+
+    #[test]
+    fn maintain_within_budget() {
+
+        let mut qt = tree_with_removed();
+
+        let mut merged = 0;
+        for _ in 0..1000 {
+            merged += qt.maintain(Duration::from_micros(50));
+        }
+
+        assert!(merged > 0);
+
+        let mut expected = tree_with_removed();
+        for _ in 0..=expected.max_depth {
+            expected.cleanup();
+        }
+
+        assert_eq!(qt.nodes.elements_count(), expected.nodes.elements_count());
+    }
+
+
+    #[test]
+    fn maintain_stops_on_clean_tree() {
+
+        let mut qt = tree_with_removed();
+
+        while qt.maintain_steps(64) > 0 {}
+        qt.maintain(Duration::from_secs(10));
+
+        // a whole pass changed nothing, so no node is looked at again
+        assert!(qt.maintain_idle);
+        assert_eq!(qt.maintain_cursor, 0);
+        assert_eq!(qt.maintain(Duration::from_secs(10)), 0);
+        assert_eq!(qt.maintain_steps(1_000_000), 0);
+        assert_eq!(qt.maintain_steps(1), 0);
+        assert_eq!(qt.maintain_cursor, 0);
+
+        // until something changes
+        let id = qt.insert(1000, Rect::new(3, 100, 1, 1));
+        assert!(!qt.maintain_idle);
+        qt.maintain_steps(1);
+        assert_eq!(qt.maintain_cursor, 1);
+
+        qt.maintain(Duration::from_secs(10));
+        assert!(qt.maintain_idle);
+
+        qt.remove(id);
+        assert!(!qt.maintain_idle);
+        qt.maintain(Duration::from_secs(10));
+        assert!(qt.maintain_idle);
+
+        // an update inside the margin links nothing
+        let id = qt.insert_dynamic(1001, Rect::new(3, 100, 1, 1), 4);
+        qt.maintain(Duration::from_secs(10));
+        qt.update(id, Rect::new(4, 101, 1, 1));
+        assert!(qt.maintain_idle);
+        qt.update(id, Rect::new(200, 200, 1, 1));
+        assert!(!qt.maintain_idle);
+    }
+
+
+    #[test]
+    fn maintain_compacts() {
+
+        let rect = Rect::from_points(Point {x: 0, y: 0}, Point { x: 256, y: 256} );
+
+        let mut qt = QuadTree::<i32, Sum>::with_aggregate(rect);
+        qt.set_elements_per_node(2);
+
+        let events = std::sync::Arc::new(std::sync::Mutex::new(HashMap::new()));
+        qt.set_observer(Box::new(Balance(events.clone())));
+
+        let mut ids = vec![];
+        for i in 0..300 {
+            ids.push(qt.insert(i, Rect::new((i * 37) % 250, (i * 91) % 250 + 5, 1, 1)));
+        }
+
+        // remove the first ones, so the free slots are before the live ones
+        for &id in &ids[..250] {
+            qt.remove(id);
+        }
+
+        let query_r = Rect::new(20, 200, 150, 150);
+        let mut before = vec![];
+        qt.query_ids(query_r, -1, &mut before);
+        before.sort();
+
+        let nodes = qt.nodes.data_len();
+        let element_nodes = qt.element_nodes.data_len();
+
+        qt.maintain(Duration::from_secs(10));
+
+        assert_eq!(qt.nodes.data_len(), qt.nodes.elements_count());
+        assert!(qt.nodes.data_len() < nodes);
+        assert_eq!(qt.element_nodes.data_len(), qt.element_nodes.elements_count());
+        assert!(qt.element_nodes.data_len() < element_nodes);
+
+        let mut after = vec![];
+        qt.query_ids(query_r, -1, &mut after);
+        after.sort();
+
+        assert_eq!(before, after);
+        assert_eq!(qt.count_in(query_r), after.len());
+        assert_eq!(qt.aggregate_in(query_r), Sum(after.iter().map(|&id| *qt.get(id).unwrap()).sum()));
+
+        // the observer knows every element by the leaf it is in now
+        let mut in_leaves = HashMap::new();
+        for leaf in qt.all_leaves() {
+            let mut elm_node_index = qt.nodes[leaf.node_index].first_child;
+            while elm_node_index != -1 {
+                in_leaves.insert((qt.element_nodes[elm_node_index].elm_id, leaf.node_index), 1);
+                elm_node_index = qt.element_nodes[elm_node_index].next;
+            }
+        }
+
+        events.lock().unwrap().retain(|_, &mut v| v != 0);
+        assert_eq!(*events.lock().unwrap(), in_leaves);
+
+        // still works as a tree
+        let id = qt.insert(1000, Rect::new(5, 250, 1, 1));
+        qt.remove(ids[299]);
+        assert_eq!(qt.get(id), Some(&1000));
+        assert_eq!(qt.len(), 50);
+    }
+
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Sum(i32);
+
+    impl Aggregate<i32> for Sum {
+        fn identity() -> Self {
+            Sum(0)
+        }
+
+        fn combine(&self, other: &Self) -> Self {
+            Sum(self.0 + other.0)
+        }
+
+        fn from_element(element: &i32) -> Self {
+            Sum(*element)
+        }
+    }
+
+
+    // Number of times each element entered each leaf, less the times it left
+    struct Balance(std::sync::Arc<std::sync::Mutex<HashMap::<(ElementId, i32), i32>>>);
+
+    impl TreeObserver for Balance {
+        fn on_element_enter_leaf(&mut self, element_id: ElementId, leaf: i32) {
+            *self.0.lock().unwrap().entry((element_id, leaf)).or_insert(0) += 1;
+        }
+
+        fn on_element_leave_leaf(&mut self, element_id: ElementId, leaf: i32) {
+            *self.0.lock().unwrap().entry((element_id, leaf)).or_insert(0) -= 1;
+        }
+    }
+}
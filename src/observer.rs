@@ -0,0 +1,462 @@
+use crate::*;
+
+
+/// Callbacks for changes to the structure of a QuadTree, see ['QuadTree::set_observer()'].
+/// Nodes and leaves are node indices, the same as Leaf::node_index. All callbacks do nothing by default
+pub trait TreeObserver {
+
+    /// Leaf node with rect became a branch with 4 new leaves. Its elements leave it before this,
+    /// and enter the new leaves after. Also called for the root, node 0, when it grows,
+    /// then one of the 4 children is the old root. If that was a branch, it is reported as moved there first
+    fn on_split(&mut self, _node: i32, _rect: Rect) {}
+
+    /// The 4 children of branch node were removed, and it is a leaf again.
+    /// Elements in the children leave them before this
+    fn on_merge(&mut self, _node: i32) {}
+
+    /// Nodes got new indices, as (old, new) pairs. They keep their rects, and branches keep their children.
+    /// All pairs move at once, and a node at new that is not moved itself is gone.
+    /// Leaves that move also see their elements leave them before this and enter them after
+    fn on_nodes_moved(&mut self, _moved: &[(i32, i32)]) {}
+
+    fn on_element_enter_leaf(&mut self, _element_id: ElementId, _leaf: i32) {}
+
+    fn on_element_leave_leaf(&mut self, _element_id: ElementId, _leaf: i32) {}
+}
+
+
+// Events recorded while a transaction runs, the observer only gets them if it commits
+#[derive(Default)]
+pub(crate) struct EventLog(Vec::<Event>);
+
+enum Event {
+    Split(i32, Rect),
+    Merge(i32),
+    Moved(Vec::<(i32, i32)>),
+    Enter(ElementId, i32),
+    Leave(ElementId, i32),
+}
+
+
+impl TreeObserver for EventLog {
+    fn on_split(&mut self, node: i32, rect: Rect) {
+        self.0.push(Event::Split(node, rect));
+    }
+
+    fn on_merge(&mut self, node: i32) {
+        self.0.push(Event::Merge(node));
+    }
+
+    fn on_nodes_moved(&mut self, moved: &[(i32, i32)]) {
+        self.0.push(Event::Moved(moved.to_vec()));
+    }
+
+    fn on_element_enter_leaf(&mut self, element_id: ElementId, leaf: i32) {
+        self.0.push(Event::Enter(element_id, leaf));
+    }
+
+    fn on_element_leave_leaf(&mut self, element_id: ElementId, leaf: i32) {
+        self.0.push(Event::Leave(element_id, leaf));
+    }
+}
+
+
+impl<T, A: Aggregate<T>> QuadTree<T, A> {
+
+    /// Observer called on every split, merge, node move and element entering or leaving a leaf from now on.
+    /// During a transaction the events are held back, and only sent if it commits
+    pub fn set_observer(&mut self, observer: Box<dyn TreeObserver + Send + Sync>) {
+        self.observer = Some(observer);
+    }
+
+    /// Remove the observer and return it
+    pub fn take_observer(&mut self) -> Option<Box<dyn TreeObserver + Send + Sync>> {
+        self.observer.take()
+    }
+
+
+    pub(crate) fn notify<F>(&mut self, f: F) where F: FnOnce(&mut dyn TreeObserver) {
+        if let Some(observer) = self.active_observer() {
+            f(observer);
+        }
+    }
+
+
+    // Start holding back events, when there is an observer to send them to
+    pub(crate) fn hold_events(&mut self) {
+        if self.observer.is_some() {
+            self.held_events = Some(EventLog::default());
+        }
+    }
+
+    // Send the held back events to the observer
+    pub(crate) fn release_events(&mut self) {
+
+        let (log, observer) = match (self.held_events.take(), &mut self.observer) {
+            (Some(log), Some(observer)) => (log, observer),
+            _ => return,
+        };
+
+        for event in log.0 {
+            match event {
+                Event::Split(node, rect) => observer.on_split(node, rect),
+                Event::Merge(node) => observer.on_merge(node),
+                Event::Moved(moved) => observer.on_nodes_moved(&moved),
+                Event::Enter(element_id, leaf) => observer.on_element_enter_leaf(element_id, leaf),
+                Event::Leave(element_id, leaf) => observer.on_element_leave_leaf(element_id, leaf),
+            }
+        }
+    }
+
+    pub(crate) fn drop_events(&mut self) {
+        self.held_events = None;
+    }
+
+
+    // The held back events during a transaction, else the observer
+    fn active_observer(&mut self) -> Option<&mut dyn TreeObserver> {
+        match (&mut self.held_events, &mut self.observer) {
+            (Some(log), _) => Some(log),
+            (None, Some(observer)) => Some(observer.as_mut()),
+            (None, None) => None,
+        }
+    }
+
+
+    // Every element in leaf leaves it
+    pub(crate) fn notify_leave_all(&mut self, leaf: i32) {
+        self.notify_all(leaf, |o, element_id| o.on_element_leave_leaf(element_id, leaf));
+    }
+
+    // Every element in leaf enters it
+    pub(crate) fn notify_enter_all(&mut self, leaf: i32) {
+        self.notify_all(leaf, |o, element_id| o.on_element_enter_leaf(element_id, leaf));
+    }
+
+
+    fn notify_all<F>(&mut self, leaf: i32, mut f: F) where F: FnMut(&mut dyn TreeObserver, ElementId) {
+
+        // not active_observer, nodes are borrowed below
+        let observer: &mut dyn TreeObserver = match (&mut self.held_events, &mut self.observer) {
+            (Some(log), _) => log,
+            (None, Some(observer)) => observer.as_mut(),
+            (None, None) => return,
+        };
+
+        let mut element_index = self.nodes[leaf].first_child;
+
+        while element_index != -1 {
+            f(observer, self.element_nodes[element_index].elm_id);
+            element_index = self.element_nodes[element_index].next;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Split(i32),
+        Merge(i32),
+        Enter(ElementId, i32),
+        Leave(ElementId, i32),
+    }
+
+    struct Recorder(Arc<Mutex<Vec::<Event>>>);
+
+    impl TreeObserver for Recorder {
+        fn on_split(&mut self, node: i32, _rect: Rect) {
+            self.0.lock().unwrap().push(Event::Split(node));
+        }
+
+        fn on_merge(&mut self, node: i32) {
+            self.0.lock().unwrap().push(Event::Merge(node));
+        }
+
+        fn on_element_enter_leaf(&mut self, element_id: ElementId, leaf: i32) {
+            self.0.lock().unwrap().push(Event::Enter(element_id, leaf));
+        }
+
+        fn on_element_leave_leaf(&mut self, element_id: ElementId, leaf: i32) {
+            self.0.lock().unwrap().push(Event::Leave(element_id, leaf));
+        }
+    }
+
+
+    // Indices of the branches, kept only from the events
+    struct Branches(Arc<Mutex<HashSet::<i32>>>);
+
+    impl TreeObserver for Branches {
+        fn on_split(&mut self, node: i32, _rect: Rect) {
+            self.0.lock().unwrap().insert(node);
+        }
+
+        fn on_merge(&mut self, node: i32) {
+            self.0.lock().unwrap().remove(&node);
+        }
+
+        fn on_nodes_moved(&mut self, moved: &[(i32, i32)]) {
+            let mut branches = self.0.lock().unwrap();
+
+            let was_branch: Vec::<bool> = moved.iter().map(|(old, _)| branches.remove(old)).collect();
+
+            for (&(_, new), was) in moved.iter().zip(was_branch) {
+                if was {
+                    branches.insert(new);
+                }
+                else {
+                    branches.remove(&new);
+                }
+            }
+        }
+    }
+
+
+    fn branches_of<T>(qt: &QuadTree<T>) -> HashSet::<i32> {
+        qt.nodes.iter().filter(|(_, node)| node.is_branch()).map(|(i, _)| i).collect()
+    }
+
+
+    #[test]
+    fn events_follow_structure() {
+
+        let rect = Rect::from_points(Point {x: -128, y: -128}, Point { x: 128, y: 128} );
+
+        let mut qt = QuadTree::new(rect);
+
+        qt.set_elements_per_node(2);
+
+        let events = Arc::new(Mutex::new(vec![]));
+        qt.set_observer(Box::new(Recorder(events.clone())));
+
+        let a = qt.insert(1, Rect::new(10, 10, 1, 1));
+        let b = qt.insert(2, Rect::new(-10, -10, 1, 1));
+
+        // second element fills the root, which splits into nodes 1 to 4
+        assert_eq!(*events.lock().unwrap(), vec![
+            Event::Enter(a, 0),
+            Event::Enter(b, 0),
+            Event::Leave(b, 0),
+            Event::Leave(a, 0),
+            Event::Split(0),
+            Event::Enter(b, 3),
+            Event::Enter(a, 2),
+        ]);
+
+        events.lock().unwrap().clear();
+
+        qt.remove(a);
+        qt.remove(b);
+        qt.cleanup();
+
+        assert_eq!(*events.lock().unwrap(), vec![Event::Leave(a, 2), Event::Leave(b, 3), Event::Merge(0)]);
+
+        // every enter has a leave after removing everything
+        events.lock().unwrap().clear();
+
+        for i in 0..20 {
+            qt.insert(i, Rect::new(i * 10 - 100, i * 3, 15, 15));
+        }
+        qt.remove_in(Rect::new(-50, 50, 20, 20));
+        qt.retain(|_, _, &mut e| e % 2 == 0);
+
+        qt.set_auto_grow(true);
+        let far = qt.insert(100, Rect::new(300, 300, 5, 5));
+        qt.remove(far);
+        qt.shrink_to_fit();
+        assert_eq!(qt.root_rect(), rect);
+
+        qt.clear();
+
+        let mut balance = std::collections::HashMap::new();
+        for event in events.lock().unwrap().iter() {
+            match *event {
+                Event::Enter(id, leaf) => *balance.entry((id, leaf)).or_insert(0) += 1,
+                Event::Leave(id, leaf) => *balance.entry((id, leaf)).or_insert(0) -= 1,
+                _ => {}
+            }
+        }
+        assert!(balance.values().all(|&v| v == 0));
+
+        assert!(qt.take_observer().is_some());
+    }
+
+
+    #[test]
+    fn transaction_events_sent_on_commit() {
+
+        let rect = Rect::from_points(Point {x: -128, y: -128}, Point { x: 128, y: 128} );
+
+        let mut qt = QuadTree::new(rect);
+
+        qt.set_elements_per_node(2);
+
+        let events = Arc::new(Mutex::new(vec![]));
+        qt.set_observer(Box::new(Recorder(events.clone())));
+
+        let a = qt.insert(1, Rect::new(10, 10, 1, 1));
+        events.lock().unwrap().clear();
+
+        // rolled back, the observer never hears of the split
+        let res = qt.transaction(|tx| {
+            tx.insert(2, Rect::new(-10, -10, 1, 1));
+            assert!(events.lock().unwrap().is_empty());
+            Err::<(), _>(QuadTreeError::InvalidElement(-1))
+        });
+
+        assert!(res.is_err());
+        assert!(events.lock().unwrap().is_empty());
+
+        let b = qt.transaction(|tx| {
+            let b = tx.insert(2, Rect::new(-10, -10, 1, 1));
+            assert!(events.lock().unwrap().is_empty());
+            Ok::<_, QuadTreeError>(b)
+        }).unwrap();
+
+        assert_eq!(*events.lock().unwrap(), vec![
+            Event::Enter(b, 0),
+            Event::Leave(b, 0),
+            Event::Leave(a, 0),
+            Event::Split(0),
+            Event::Enter(b, 3),
+            Event::Enter(a, 2),
+        ]);
+
+        // not held back after the transaction
+        events.lock().unwrap().clear();
+        qt.remove(a);
+        assert_eq!(*events.lock().unwrap(), vec![Event::Leave(a, 2)]);
+    }
+
+
+    #[test]
+    fn branches_follow_growing_root() {
+
+        let rect = Rect::from_points(Point {x: 0, y: 0}, Point { x: 64, y: 64} );
+
+        let mut qt = QuadTree::new(rect);
+
+        qt.set_elements_per_node(2);
+        qt.set_auto_grow(true);
+
+        let branches = Arc::new(Mutex::new(HashSet::new()));
+        qt.set_observer(Box::new(Branches(branches.clone())));
+
+        for i in 0..6 {
+            qt.insert(i, Rect::new(i * 10, 50, 2, 2));
+        }
+        assert_eq!(*branches.lock().unwrap(), branches_of(&qt));
+
+        // the root branch moves into a quad of the new root, twice
+        let far = qt.insert(10, Rect::new(-100, -100, 1, 1));
+        assert_ne!(qt.root_rect(), rect);
+        assert_eq!(*branches.lock().unwrap(), branches_of(&qt));
+
+        // and back to node 0
+        qt.remove(far);
+        qt.shrink_to_fit();
+        assert_eq!(qt.root_rect(), rect);
+        assert_eq!(*branches.lock().unwrap(), branches_of(&qt));
+
+        // growing from the nodes left by clear, and shrinking back
+        qt.clear();
+        qt.cleanup();
+        let far = qt.insert(11, Rect::new(100, 100, 1, 1));
+        qt.remove(far);
+        qt.shrink_to_fit();
+        assert_eq!(*branches.lock().unwrap(), branches_of(&qt));
+        assert_eq!(qt.root_rect(), rect);
+    }
+
+
+    #[test]
+    fn branches_follow_compact() {
+
+        let rect = Rect::from_points(Point {x: 0, y: 0}, Point { x: 256, y: 256} );
+
+        let mut qt = QuadTree::new(rect);
+
+        qt.set_elements_per_node(2);
+
+        let branches = Arc::new(Mutex::new(HashSet::new()));
+        qt.set_observer(Box::new(Branches(branches.clone())));
+
+        let ids: Vec::<ElementId> = (0..40).map(|i| qt.insert(i, Rect::new(i * 6, 250 - i * 6, 1, 1))).collect();
+
+        // free blocks in the middle, so branches after them move
+        for &id in &ids[..20] {
+            qt.remove(id);
+        }
+        qt.cleanup();
+        qt.cleanup();
+
+        let before = branches_of(&qt);
+        qt.compact();
+
+        assert_ne!(branches_of(&qt), before);
+        assert_eq!(*branches.lock().unwrap(), branches_of(&qt));
+    }
+
+
+    #[test]
+    fn branches_follow_optimize_layout() {
+
+        let rect = Rect::from_points(Point {x: 0, y: 0}, Point { x: 256, y: 256} );
+
+        let mut qt = QuadTree::new(rect);
+
+        qt.set_elements_per_node(2);
+
+        let branches = Arc::new(Mutex::new(HashSet::new()));
+        qt.set_observer(Box::new(Branches(branches.clone())));
+
+        // split the right half first, so depth first order renumbers the branches
+        for i in 0..20 {
+            qt.insert(i, Rect::new(250 - i * 6, 250 - i * 6, 1, 1));
+        }
+        for i in 0..20 {
+            qt.insert(i, Rect::new(i * 6, 250 - i * 6, 1, 1));
+        }
+
+        let before = branches_of(&qt);
+        qt.optimize_layout();
+
+        assert_ne!(branches_of(&qt), before);
+        assert_eq!(*branches.lock().unwrap(), branches_of(&qt));
+    }
+
+
+    #[test]
+    fn branches_follow_maintain() {
+
+        let rect = Rect::from_points(Point {x: 0, y: 0}, Point { x: 256, y: 256} );
+
+        let mut qt = QuadTree::new(rect);
+
+        qt.set_elements_per_node(2);
+
+        let branches = Arc::new(Mutex::new(HashSet::new()));
+        qt.set_observer(Box::new(Branches(branches.clone())));
+
+        let ids: Vec::<ElementId> = (0..40).map(|i| qt.insert(i, Rect::new(i * 6, 250 - i * 6, 1, 1))).collect();
+
+        for &id in &ids[..20] {
+            qt.remove(id);
+        }
+
+        let before = branches_of(&qt);
+
+        while qt.maintain_steps(8) > 0 || qt.maintain_cursor != 0 {
+            assert_eq!(*branches.lock().unwrap(), branches_of(&qt));
+        }
+
+        assert_ne!(branches_of(&qt), before);
+        assert_eq!(*branches.lock().unwrap(), branches_of(&qt));
+    }
+}
@@ -0,0 +1,410 @@
+use std::ops::ControlFlow;
+use std::sync::Arc;
+use crate::*;
+
+
+/// Immutable quadtree where insert and remove return a new tree, and leave the old one as it was.
+/// Only the nodes on the way to the changed leaves are copied, the rest is shared with the old tree
+/// through Arc. So keeping a snapshot is cheap, and snapshots can be read from other threads without locks.
+/// Like QuadTree, elements are in every leaf they intersect, and queries report each element once
+pub struct PersistentQuadTree<T> {
+
+    root: Arc<PNode<T>>,
+
+    // Rect and element of every element id, so remove and get do not have to search the tree
+    ids: Arc<IdNode<T>>,
+
+    root_rect: Rect,
+
+    max_depth: i32,
+    elements_per_node: i32,
+
+    next_id: ElementId,
+    len: usize,
+}
+
+
+enum PNode<T> {
+    Leaf(Vec::<PEntry<T>>),
+    Branch([Arc<PNode<T>>; 4]),
+}
+
+
+struct PEntry<T> {
+    element_id: ElementId,
+    rect: Rect,
+    element: Arc<T>,
+}
+
+impl<T> Clone for PEntry<T> {
+    fn clone(&self) -> Self {
+        PEntry { element_id: self.element_id, rect: self.rect, element: self.element.clone() }
+    }
+}
+
+
+// Trie over the bits of element ids, ID_BITS per level. Leaves are ID_LEVELS down
+const ID_BITS: u32 = 4;
+const ID_LEVELS: u32 = 32 / ID_BITS;
+const ID_MASK: u32 = (1 << ID_BITS) - 1;
+
+type IdSlots<T> = [Option<(Rect, Arc<T>)>; 1 << ID_BITS];
+
+enum IdNode<T> {
+    Branch([Option<Arc<IdNode<T>>>; 1 << ID_BITS]),
+    Elements(Box<IdSlots<T>>),
+}
+
+
+impl<T> Clone for PersistentQuadTree<T> {
+
+    // Only copies the roots
+    fn clone(&self) -> Self {
+        PersistentQuadTree {
+            root: self.root.clone(),
+            ids: self.ids.clone(),
+            root_rect: self.root_rect,
+            max_depth: self.max_depth,
+            elements_per_node: self.elements_per_node,
+            next_id: self.next_id,
+            len: self.len,
+        }
+    }
+}
+
+
+// Public interface
+impl<T> PersistentQuadTree<T> {
+
+    pub fn new(rect: Rect) -> Self {
+        PersistentQuadTree {
+            root: Arc::new(PNode::Leaf(vec![])),
+            ids: Arc::new(IdNode::Branch(Default::default())),
+            root_rect: rect,
+            max_depth: 10,
+            elements_per_node: 300,
+            next_id: 0,
+            len: 0,
+        }
+    }
+
+    pub fn set_elements_per_node(&mut self, npc: i32) {
+        self.elements_per_node = i32::max(1, npc);
+    }
+
+    pub fn root_rect(&self) -> Rect {
+        self.root_rect
+    }
+
+    /// New tree with element added, and the id of the element. Ids are not reused
+    pub fn insert(&self, element: T, element_rect: Rect) -> (Self, ElementId) {
+
+        let element_id = self.next_id;
+        let element = Arc::new(element);
+
+        let mut res = self.clone();
+        res.next_id += 1;
+        res.len += 1;
+
+        res.ids = id_set(Some(&self.ids), element_id as u32, ID_LEVELS - 1, Some((element_rect, element.clone())));
+
+        // The root does not grow, elements outside it get no leaf and are not found by queries
+        if self.root_rect.intersect(element_rect) {
+            let entry = PEntry { element_id, rect: element_rect, element };
+            res.root = self.insert_node(&self.root, self.root_rect, 0, &entry);
+        }
+
+        (res, element_id)
+    }
+
+    /// New tree without the element. Branches left with only empty leaves become leaves
+    pub fn remove(&self, element_id: ElementId) -> Self {
+
+        let rect = match self.rect(element_id) {
+            Some(rect) => rect,
+            None => return self.clone(),
+        };
+
+        let mut res = self.clone();
+        res.len -= 1;
+        res.ids = id_set(Some(&self.ids), element_id as u32, ID_LEVELS - 1, None);
+
+        if self.root_rect.intersect(rect) {
+            res.root = Self::remove_node(&self.root, self.root_rect, element_id, rect);
+        }
+
+        res
+    }
+
+    pub fn get(&self, element_id: ElementId) -> Option<&T> {
+        self.id_slot(element_id).map(|(_, element)| &**element)
+    }
+
+    pub fn rect(&self, element_id: ElementId) -> Option<Rect> {
+        self.id_slot(element_id).map(|&(rect, _)| rect)
+    }
+
+    pub fn contains(&self, element_id: ElementId) -> bool {
+        self.id_slot(element_id).is_some()
+    }
+
+    /// Number of elements in the tree
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Like ['QuadTree::query_with()'], each element is reported once even when it is in more than one leaf
+    pub fn query_with<'a, F>(&'a self, query_r: Rect, mut f: F) where F: FnMut(ElementId, &'a Rect, &'a T) -> ControlFlow<()> {
+
+        if !self.root_rect.intersect(query_r) {
+            return;
+        }
+
+        let _ = self.query_node_with(&self.root, self.root_rect, self.root_rect, query_r, &mut f);
+    }
+
+    /// Like ['QuadTree::query_ids()']
+    pub fn query_ids(&self, query_r: Rect, omit_elm: i32, output: &mut Vec<ElementId>) {
+        self.query_with(query_r, |element_id, _, _| {
+            if element_id != omit_elm {
+                output.push(element_id);
+            }
+            ControlFlow::Continue(())
+        });
+    }
+
+    /// Like ['QuadTree::query_refs()']
+    pub fn query_refs<'a>(&'a self, query_r: Rect, omit_elm: i32, output: &mut Vec<&'a T>) {
+        self.query_with(query_r, |element_id, _, data| {
+            if element_id != omit_elm {
+                output.push(data);
+            }
+            ControlFlow::Continue(())
+        });
+    }
+}
+
+
+// Private functions
+impl<T> PersistentQuadTree<T> {
+
+    fn id_slot(&self, element_id: ElementId) -> Option<&(Rect, Arc<T>)> {
+
+        if element_id < 0 {
+            return None;
+        }
+
+        let mut node = &*self.ids;
+        let mut level = ID_LEVELS - 1;
+
+        loop {
+            let slot = (element_id as u32 >> (level * ID_BITS)) & ID_MASK;
+
+            match node {
+                IdNode::Branch(children) => {
+                    node = children[slot as usize].as_deref()?;
+                    level -= 1;
+                },
+                IdNode::Elements(elements) => return elements[slot as usize].as_ref(),
+            }
+        }
+    }
+
+
+    fn insert_node(&self, node: &Arc<PNode<T>>, node_rect: Rect, depth: i32, entry: &PEntry<T>) -> Arc<PNode<T>> {
+
+        match &**node {
+            PNode::Leaf(entries) => {
+                let mut entries = entries.clone();
+                entries.push(entry.clone());
+
+                self.make_leaf(entries, node_rect, depth)
+            },
+            PNode::Branch(children) => {
+                let mut children = children.clone();
+                let locations = node_rect.location_quads();
+
+                for i in 0..4 {
+                    if locations[i].intersect(entry.rect) {
+                        children[i] = self.insert_node(&children[i], locations[i], depth + 1, entry);
+                    }
+                }
+
+                Arc::new(PNode::Branch(children))
+            }
+        }
+    }
+
+
+    // Leaf with entries, split like QuadTree::node_insert if it has too many
+    fn make_leaf(&self, entries: Vec::<PEntry<T>>, node_rect: Rect, depth: i32) -> Arc<PNode<T>> {
+
+        if entries.len() < self.elements_per_node as usize || depth >= self.max_depth {
+            return Arc::new(PNode::Leaf(entries));
+        }
+
+        let locations = node_rect.location_quads();
+
+        let children = locations.map(|location| {
+            let child_entries = entries.iter().filter(|e| location.intersect(e.rect)).cloned().collect();
+            self.make_leaf(child_entries, location, depth + 1)
+        });
+
+        Arc::new(PNode::Branch(children))
+    }
+
+
+    fn remove_node(node: &Arc<PNode<T>>, node_rect: Rect, element_id: ElementId, rect: Rect) -> Arc<PNode<T>> {
+
+        match &**node {
+            PNode::Leaf(entries) => {
+                Arc::new(PNode::Leaf(entries.iter().filter(|e| e.element_id != element_id).cloned().collect()))
+            },
+            PNode::Branch(children) => {
+                let mut children = children.clone();
+                let locations = node_rect.location_quads();
+
+                for i in 0..4 {
+                    if locations[i].intersect(rect) {
+                        children[i] = Self::remove_node(&children[i], locations[i], element_id, rect);
+                    }
+                }
+
+                if children.iter().all(|c| matches!(&**c, PNode::Leaf(entries) if entries.is_empty())) {
+                    return Arc::new(PNode::Leaf(vec![]));
+                }
+
+                Arc::new(PNode::Branch(children))
+            }
+        }
+    }
+
+
+    // See QuadTree::query_node_with
+    fn query_node_with<'a, F>(&'a self, node: &'a PNode<T>, node_rect: Rect, owned: Rect, query_r: Rect, f: &mut F) -> ControlFlow<()>
+    where F: FnMut(ElementId, &'a Rect, &'a T) -> ControlFlow<()> {
+
+        match node {
+            PNode::Leaf(entries) => {
+                for entry in entries {
+                    if !query_r.intersect(entry.rect) {
+                        continue;
+                    }
+
+                    let overlap = entry.rect.intersection(query_r).intersection(self.root_rect);
+
+                    if owned.contains_point(Point::new(overlap.left, overlap.top)) {
+                        f(entry.element_id, &entry.rect, &entry.element)?;
+                    }
+                }
+            },
+            PNode::Branch(children) => {
+                let locations = node_rect.location_quads();
+                let owned_quads = node_rect.owned_quads(owned);
+
+                for i in 0..4 {
+                    if locations[i].intersect(query_r) {
+                        self.query_node_with(&children[i], locations[i], owned_quads[i], query_r, f)?;
+                    }
+                }
+            }
+        }
+
+        ControlFlow::Continue(())
+    }
+}
+
+
+// Copy of node with slot of element_id set to value, made if node is None
+fn id_set<T>(node: Option<&Arc<IdNode<T>>>, element_id: u32, level: u32, value: Option<(Rect, Arc<T>)>) -> Arc<IdNode<T>> {
+
+    let slot = ((element_id >> (level * ID_BITS)) & ID_MASK) as usize;
+
+    if level == 0 {
+        let mut elements: Box<IdSlots<T>> = match node.map(|n| &**n) {
+            Some(IdNode::Elements(elements)) => elements.clone(),
+            _ => Default::default(),
+        };
+
+        elements[slot] = value;
+        return Arc::new(IdNode::Elements(elements));
+    }
+
+    let mut children: [Option<Arc<IdNode<T>>>; 1 << ID_BITS] = match node.map(|n| &**n) {
+        Some(IdNode::Branch(children)) => children.clone(),
+        _ => Default::default(),
+    };
+
+    children[slot] = Some(id_set(children[slot].as_ref(), element_id, level - 1, value));
+
+    Arc::new(IdNode::Branch(children))
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn snapshots_are_unchanged() {
+
+        let rect = Rect::from_points(Point {x: -128, y: -128}, Point { x: 128, y: 128} );
+
+        let mut empty = PersistentQuadTree::new(rect);
+        empty.set_elements_per_node(2);
+
+        let mut tree = empty.clone();
+        let mut ids = vec![];
+
+        for i in 0..20 {
+            let (next, id) = tree.insert(i, Rect::new(i * 10 - 100, i * 5, 3, 3));
+            tree = next;
+            ids.push(id);
+        }
+
+        let (with_wide, wide) = tree.insert(100, Rect::from_points(Point::new(-50, -50), Point::new(50, 50)));
+        let removed = with_wide.remove(ids[3]).remove(ids[4]);
+
+        assert_eq!((empty.len(), tree.len(), with_wide.len(), removed.len()), (0, 20, 21, 19));
+
+        let mut found = vec![];
+        tree.query_ids(rect, -1, &mut found);
+        found.sort();
+        assert_eq!(found, ids);
+
+        found.clear();
+        removed.query_ids(rect, -1, &mut found);
+        assert_eq!(found.len(), 19);
+        assert!(found.contains(&wide) && !found.contains(&ids[3]));
+
+        assert_eq!(tree.get(ids[3]), Some(&3));
+        assert_eq!(removed.get(ids[3]), None);
+        assert_eq!(removed.rect(wide), Some(Rect::from_points(Point::new(-50, -50), Point::new(50, 50))));
+
+        // removing from the top left quad copies it, the other quads are shared
+        match (&*with_wide.root, &*removed.root) {
+            (PNode::Branch(a), PNode::Branch(b)) => {
+                assert!(!Arc::ptr_eq(&a[0], &b[0]));
+                assert!(Arc::ptr_eq(&a[1], &b[1]));
+            },
+            _ => panic!("root should be split"),
+        }
+
+        // read a snapshot from another thread
+        let snapshot = removed.clone();
+        let handle = std::thread::spawn(move || {
+            let mut refs = vec![];
+            snapshot.query_refs(Rect::from_points(Point::new(-80, 0), Point::new(-40, 30)), -1, &mut refs);
+            refs.into_iter().copied().collect::<Vec<i32>>()
+        });
+
+        let mut values = handle.join().unwrap();
+        values.sort();
+        assert_eq!(values, vec![2, 5, 6, 100]);
+    }
+}
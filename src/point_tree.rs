@@ -0,0 +1,439 @@
+use crate::free_list::FreeList;
+use crate::*;
+
+
+/// Quadtree for elements that are single points.
+/// Each point is stored in exactly one leaf, points on the middle line of a node
+/// go to the left and bottom child, the same as the cells of QuadTree and RegionQuadTree.
+/// So no element is stored more than once, and queries do not need to filter out elements already found
+pub struct PointQuadTree<T> {
+
+    // All points in the tree. Also the element nodes, next links points in the same leaf
+    // Index is the same as into data, since they are always inserted and erased together
+    points: FreeList<ElmPoint>,
+
+    // All nodes in tree, first node is the root
+    // Same layout as in QuadTree, leafs first child is index into points
+    nodes: BlockList::<Node>,
+
+    // Actual data inserted into tree
+    data: FreeList::<T>,
+
+    // Rect for the root
+    root_rect: Rect,
+
+    max_depth: i32,
+    elements_per_node: i32,
+}
+
+
+// Public interface
+impl<T> PointQuadTree<T> {
+
+    pub fn new(rect: Rect) -> Self {
+
+        let nodes = BlockList::new(Node::leaf());
+
+        PointQuadTree {
+            points: FreeList::new(),
+            nodes,
+            data: FreeList::new(),
+            root_rect: rect,
+            max_depth: 10,
+            elements_per_node: 300,
+        }
+    }
+
+    /// Insert element at point. The root does not grow, a point outside it is stored,
+    /// but no query finds it
+    pub fn insert(&mut self, element: T, point: Point) -> i32 {
+
+        let data_id = self.data.insert(element);
+
+        let element_id = self.points.insert(ElmPoint {
+            point,
+            next: -1
+        });
+
+        assert_eq!(data_id, element_id);
+
+        // Only points inside the root get a leaf
+        if self.root_rect.contains_point(point) {
+            let (leaf_index, leaf_rect, depth) = self.find_leaf(point);
+
+            self.points[element_id].next = self.nodes[leaf_index].first_child;
+            self.nodes[leaf_index].first_child = element_id;
+            self.nodes[leaf_index].count += 1;
+
+            self.split(leaf_index, leaf_rect, depth);
+        }
+
+        element_id
+    }
+
+    pub fn set_elements_per_node(&mut self, npc: i32) {
+        self.elements_per_node = i32::max(1, npc);
+    }
+
+
+    /// Like ['QuadTree::remove()'], empty leaves stay until ['cleanup()']
+    pub fn remove(&mut self, element_id: i32) {
+
+        let point = self.points[element_id].point;
+
+        if self.root_rect.contains_point(point) {
+            let (leaf_index, _, _) = self.find_leaf(point);
+
+            let mut element_index = self.nodes[leaf_index].first_child;
+            let mut prev_index = -1;
+
+            while element_index != -1 && element_index != element_id {
+                prev_index = element_index;
+                element_index = self.points[element_index].next;
+            }
+
+            if element_index != -1 {
+                let next_index = self.points[element_index].next;
+
+                if prev_index == -1 {
+                    self.nodes[leaf_index].first_child = next_index;
+                } else {
+                    self.points[prev_index].next = next_index;
+                }
+
+                self.nodes[leaf_index].count -= 1;
+            }
+        }
+
+        self.data.erase(element_id);
+        self.points.erase(element_id);
+    }
+
+
+    /// Clean the tree by making branches with only empty leaf children into leafs.
+    /// Unlike QuadTree this works bottom up, so a single call merges all levels
+    pub fn cleanup(&mut self) {
+        self.cleanup_node(0);
+    }
+
+
+    /// Clear all data from the tree. Does not clear the structure, see QuadTree::clear
+    pub fn clear(&mut self) {
+
+        self.points.clear();
+        self.data.clear();
+
+        for i in 0..self.nodes.data_len() {
+            if self.nodes.is_live(i) && self.nodes[i].is_leaf() {
+                self.nodes[i].count = 0;
+                self.nodes[i].first_child = -1;
+            }
+        }
+    }
+
+    pub fn max_element_id(&self) -> usize {
+        self.points.data_len() as usize
+    }
+
+    pub fn all_leaves(&self) -> Vec::<Leaf> {
+        let mut res = vec![];
+        self.collect_leaves(0, self.root_rect, 0, &mut res);
+        res
+    }
+}
+
+
+// Public interface for query
+impl<T> PointQuadTree<T> {
+
+    /// Ids of all elements at the given point. Since a point has exactly one leaf
+    /// only a single path from the root is visited
+    pub fn query_p_ids(&self, query_p: Point, omit_elm: i32, output: &mut Vec<ElementId>) {
+        self.visit_point(query_p, omit_elm, &mut |element_id| output.push(element_id));
+    }
+
+    pub fn query_ids(&self, query_r: Rect, omit_elm: i32, output: &mut Vec<ElementId>) {
+        self.visit_node(0, self.root_rect, query_r, omit_elm, &mut |element_id| output.push(element_id));
+    }
+
+    pub fn query_p_refs<'a>(&'a self, query_p: Point, omit_elm: i32, output: &mut Vec<&'a T>) {
+        self.visit_point(query_p, omit_elm, &mut |element_id| output.push(&self.data[element_id]));
+    }
+
+    pub fn query_refs<'a>(&'a self, query_r: Rect, omit_elm: i32, output: &mut Vec<&'a T>) {
+        self.visit_node(0, self.root_rect, query_r, omit_elm, &mut |element_id| output.push(&self.data[element_id]));
+    }
+}
+
+
+// Cloning queries, like those of QuadTree in query.rs
+impl<T: Clone> PointQuadTree<T> {
+
+    pub fn query_p(&self, query_p: Point, omit_elm: i32, output: &mut Vec<T>) {
+        self.visit_point(query_p, omit_elm, &mut |element_id| output.push(self.data[element_id].clone()));
+    }
+
+    pub fn query(&self, query_r: Rect, omit_elm: i32, output: &mut Vec<T>) {
+        self.visit_node(0, self.root_rect, query_r, omit_elm, &mut |element_id| output.push(self.data[element_id].clone()));
+    }
+}
+
+
+// Private functions
+impl<T> PointQuadTree<T> {
+
+    // Walk from the root to the leaf that owns point
+    fn find_leaf(&self, point: Point) -> (i32, Rect, i32) {
+        let mut node_index = 0;
+        let mut node_rect = self.root_rect;
+        let mut depth = 0;
+
+        while self.nodes[node_index].is_branch() {
+            let quad = node_rect.cell_quad(point);
+            node_index = self.nodes[node_index].first_child + quad as i32;
+            node_rect = node_rect.location_quads()[quad];
+            depth += 1;
+        }
+
+        (node_index, node_rect, depth)
+    }
+
+
+    // Split leaf if too big and not too far down. Keeps splitting children
+    // when all points ends up in the same child
+    fn split(&mut self, leaf_index: i32, leaf_rect: Rect, depth: i32) {
+
+        if self.nodes[leaf_index].count < self.elements_per_node || depth >= self.max_depth {
+            return;
+        }
+
+        let first_child = self.nodes.alloc_block([Node::leaf(), Node::leaf(), Node::leaf(), Node::leaf()]);
+
+        let mut element_index = self.nodes[leaf_index].first_child;
+
+        // make leaf into a branch
+        self.nodes[leaf_index].first_child = first_child;
+        self.nodes[leaf_index].count = -1;
+
+        // move each point to the child owning it
+        while element_index != -1 {
+            let next_index = self.points[element_index].next;
+            let child_index = first_child + leaf_rect.cell_quad(self.points[element_index].point) as i32;
+
+            self.points[element_index].next = self.nodes[child_index].first_child;
+            self.nodes[child_index].first_child = element_index;
+            self.nodes[child_index].count += 1;
+
+            element_index = next_index;
+        }
+
+        let locations = leaf_rect.location_quads();
+        for (i, &location) in locations.iter().enumerate() {
+            self.split(first_child + i as i32, location, depth + 1);
+        }
+    }
+
+
+    // Returns true if node is, or has become, an empty leaf
+    fn cleanup_node(&mut self, node_index: i32) -> bool {
+
+        if self.nodes[node_index].is_leaf() {
+            return self.nodes[node_index].count == 0;
+        }
+
+        let first_child = self.nodes[node_index].first_child;
+
+        let mut empty_children = 0;
+        for i in 0..4 {
+            if self.cleanup_node(first_child + i) {
+                empty_children += 1;
+            }
+        }
+
+        if empty_children < 4 {
+            return false;
+        }
+
+        self.nodes[node_index].first_child = -1;
+        self.nodes[node_index].count = 0;
+
+        self.nodes.free_block(first_child);
+
+        true
+    }
+
+
+    fn collect_leaves(&self, node_index: i32, node_rect: Rect, depth: i32, res: &mut Vec::<Leaf>) {
+
+        if self.nodes[node_index].is_leaf() {
+            res.push(Leaf { node_index, depth, rect: node_rect });
+            return;
+        }
+
+        let first_child = self.nodes[node_index].first_child;
+        let locations = node_rect.location_quads();
+
+        for (i, &location) in locations.iter().enumerate() {
+            self.collect_leaves(first_child + i as i32, location, depth + 1, res);
+        }
+    }
+}
+
+
+impl<T> PointQuadTree<T> {
+
+    // Call f with the id of each element at query_p
+    fn visit_point<F: FnMut(ElementId)>(&self, query_p: Point, omit_elm: i32, f: &mut F) {
+
+        if !self.root_rect.contains_point(query_p) {
+            return;
+        }
+
+        let (leaf_index, _, _) = self.find_leaf(query_p);
+
+        let mut element_index = self.nodes[leaf_index].first_child;
+
+        while element_index != -1 {
+            let elm = &self.points[element_index];
+
+            if element_index != omit_elm && elm.point == query_p {
+                f(element_index);
+            }
+
+            element_index = elm.next;
+        }
+    }
+
+
+    // Call f with the id of each element below node_index inside query_r
+    fn visit_node<F: FnMut(ElementId)>(&self, node_index: i32, node_rect: Rect, query_r: Rect, omit_elm: i32, f: &mut F) {
+
+        let node = &self.nodes[node_index];
+
+        if node.is_leaf() {
+            let mut element_index = node.first_child;
+
+            while element_index != -1 {
+                let elm = &self.points[element_index];
+
+                if element_index != omit_elm && query_r.contains_point(elm.point) {
+                    f(element_index);
+                }
+
+                element_index = elm.next;
+            }
+            return;
+        }
+
+        let quads = Rect::element_quad_locations(node_rect, query_r);
+        let locations = node_rect.location_quads();
+
+        for i in 0..4 {
+            if quads[i] {
+                self.visit_node(node.first_child + i as i32, locations[i], query_r, omit_elm, f);
+            }
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn sorted(mut res: Vec::<i32>) -> Vec::<i32> {
+        res.sort();
+        res
+    }
+
+    #[test]
+    fn midline_point_in_one_leaf() {
+
+        let rect = Rect::from_points(Point {x: -128, y: -128}, Point { x: 128, y: 128} );
+
+        let mut qt = PointQuadTree::<i32>::new(rect);
+
+        qt.set_elements_per_node(2);
+
+        qt.insert(1, Point::new(0, 0));
+        qt.insert(2, Point::new(10, 10));
+        qt.insert(3, Point::new(-10, -10));
+
+        let count: i32 = qt.all_leaves().iter().map(|l| qt.nodes[l.node_index].count).sum();
+        assert_eq!(count, 3);
+
+        // (0, 0) is on both middle lines and belongs to the bottom left
+        let (leaf_index, leaf_rect, _) = qt.find_leaf(Point::new(0, 0));
+        assert!(leaf_rect.right <= 0 && leaf_rect.top <= 0);
+        assert_eq!(qt.nodes[leaf_index].first_child, 0);
+
+        let mut res = vec![];
+        qt.query_p_ids(Point::new(0, 0), -1, &mut res);
+        assert_eq!(res, vec![0]);
+
+        // outside the root, stored but never found
+        let outside = qt.insert(4, Point::new(200, 0));
+        res.clear();
+        qt.query_ids(Rect::new(150, 50, 100, 100), -1, &mut res);
+        assert!(res.is_empty());
+        assert_eq!(outside, 3);
+    }
+
+
+    #[test]
+    fn query_rect_and_point() {
+
+        let rect = Rect::from_points(Point {x: -128, y: -128}, Point { x: 128, y: 128} );
+
+        let mut qt = PointQuadTree::<i32>::new(rect);
+
+        qt.set_elements_per_node(4);
+
+        for i in -10..10 {
+            qt.insert(i, Point::new(i * 5, i * 5));
+        }
+
+        let mut res = vec![];
+        qt.query(Rect::from_points(Point::new(-10, -10), Point::new(10, 10)), -1, &mut res);
+        assert_eq!(sorted(res), vec![-2, -1, 0, 1, 2]);
+
+        let mut res = vec![];
+        qt.query_p(Point::new(0, 0), -1, &mut res);
+        assert_eq!(res, vec![0]);
+
+        let mut res = vec![];
+        qt.query_p(Point::new(1, 0), -1, &mut res);
+        assert!(res.is_empty());
+    }
+
+
+    #[test]
+    fn remove_cleanup() {
+
+        let rect = Rect::from_points(Point {x: -128, y: -128}, Point { x: 128, y: 128} );
+
+        let mut qt = PointQuadTree::<i32>::new(rect);
+
+        qt.set_elements_per_node(2);
+
+        let ids: Vec::<i32> = (0..8).map(|i| qt.insert(i, Point::new(100 - i, 100 - i))).collect();
+
+        assert!(qt.nodes[0].is_branch());
+
+        for &id in &ids[1..] {
+            qt.remove(id);
+        }
+
+        let mut res = vec![];
+        qt.query(rect, -1, &mut res);
+        assert_eq!(res, vec![0]);
+
+        qt.remove(ids[0]);
+        qt.cleanup();
+
+        assert!(qt.nodes[0].is_leaf());
+        assert_eq!(qt.nodes.elements_count(), 1);
+    }
+}
@@ -1,3 +1,4 @@
+use std::ops::ControlFlow;
 use crate::data::*;
 use crate::*;
 
@@ -20,41 +21,46 @@ impl Query {
 
 
 // Public interface for query
-impl<'a, T: std::fmt::Debug + Copy> QuadTree<T> {
-
-    pub fn query_p(&mut self, query_p: Point, omit_elm: i32, output: &mut Vec<T>){
-
-        self.query(Point::to_rect(query_p), omit_elm, output);
+impl<T, A: Aggregate<T>> QuadTree<T, A> {
 
+    /// Ids of all elements at query_p, except omit_elm
+    pub fn query_p_ids(&self, query_p: Point, omit_elm: i32, output: &mut Vec<ElementId>) {
+        self.query_ids(Point::to_rect(query_p), omit_elm, output);
     }
 
-    pub fn query(&mut self, query_r: Rect, omit_elm: i32, output: &mut Vec<T>){
-
-        self.ensure_query_tmp_buffer_size();
-
-        let root_rect = self.root_rect;
-        self.query_node_box_rect(root_rect, query_r, omit_elm, output);
-
-
-        // clear tmp buffer
-        for i in 0..self.query_tmp_buffer.len() {
-            self.query_tmp_buffer[i] = false;
-        }
+    /// Ids of all elements intersecting query_r, except omit_elm
+    pub fn query_ids(&self, query_r: Rect, omit_elm: i32, output: &mut Vec<ElementId>) {
+        self.query_with(query_r, |element_id, _, _| {
+            if element_id != omit_elm {
+                output.push(element_id);
+            }
+            ControlFlow::Continue(())
+        });
+    }
 
+    pub fn query_p_refs<'a>(&'a self, query_p: Point, omit_elm: i32, output: &mut Vec<&'a T>) {
+        self.query_refs(Point::to_rect(query_p), omit_elm, output);
     }
 
+    /// References to all elements intersecting query_r, except omit_elm
+    pub fn query_refs<'a>(&'a self, query_r: Rect, omit_elm: i32, output: &mut Vec<&'a T>) {
+        self.query_with(query_r, |element_id, _, data| {
+            if element_id != omit_elm {
+                output.push(data);
+            }
+            ControlFlow::Continue(())
+        });
+    }
 
-    fn query_node_box_rect(&mut self, node_rect: Rect, query_r: Rect, omit_elm: i32, data_vec: &mut  Vec::<T>) {
+    pub fn all_leaves(&self) -> Vec::<Leaf> {
+        self.find_leaves(0, self.root_rect, self.root_rect, 0)
+    }
 
-        let leaves = self.find_leaves(0, self.root_rect, query_r, 0);
 
-        for &leaf in &leaves {
-            self.find_element(leaf.node_index, node_rect, query_r, omit_elm, data_vec);
-        }
-    }
+    pub fn get_leaf_elements(&mut self, node_index: i32, data_vec: &mut Vec::<i32>) {
 
+        self.ensure_query_tmp_buffer_size();
 
-    fn find_element(&mut self, node_index: i32, node_rect: Rect, query_r: Rect, omit_elm: i32, data_vec: &mut  Vec::<T>) {
 
         let leaf_node = &self.nodes[node_index];
 
@@ -63,63 +69,149 @@ impl<'a, T: std::fmt::Debug + Copy> QuadTree<T> {
         while elm_node_index != -1 {
             let elm_node = &self.element_nodes[elm_node_index];
             let element_id = elm_node.elm_id;
-            let elm_rect = &self.elm_rects[element_id];
-            // Not omit and not already added to output and intersect query
-            if omit_elm != element_id && !self.query_tmp_buffer[element_id as usize] && query_r.intersect(elm_rect.rect) {
+            // Not already added to output
+            if !self.query_tmp_buffer[element_id as usize] {
 
                 // add to found element for this query
                 self.query_tmp_buffer[element_id as usize] = true;
 
-                data_vec.push(self.data[self.elm_rects[element_id].data_id]);
-                return;
+                data_vec.push(element_id);
             }
             elm_node_index = elm_node.next;
         }
+
+         // clear tmp buffer
+        for i in 0..self.query_tmp_buffer.len() {
+            self.query_tmp_buffer[i] = false;
+        }
     }
+}
 
 
-    pub fn all_leaves(&self) -> Vec::<Leaf> {
-        self.find_leaves(0, self.root_rect, self.root_rect, 0)
+// Allocation free queries
+impl<T, A: Aggregate<T>> QuadTree<T, A> {
+
+    /// Call f for each element intersecting query_r, stops when f returns Break.
+    /// Elements in more than one leaf are only reported in the leaf owning the top left corner
+    /// of where element, query and root overlap. So no buffer of found elements is needed
+    pub fn query_with<'a, F>(&'a self, query_r: Rect, mut f: F) where F: FnMut(ElementId, &'a Rect, &'a T) -> ControlFlow<()> {
+
+        if !self.root_rect.intersect(query_r) {
+            return;
+        }
+
+        let _ = self.query_node_with(0, self.root_rect, self.root_rect, query_r, &mut f);
     }
 
 
-    fn ensure_query_tmp_buffer_size(&mut self) {
-        // make sure our query tmp buffer is big enough
-        if self.query_tmp_buffer.len() < self.elm_rects.elements_count() as usize {
-            for _ in 0..(self.elm_rects.elements_count() as usize - self.query_tmp_buffer.len() as usize) {
-                self.query_tmp_buffer.push(false);
+    // owned is the part of node_rect this node owns, see Rect::cell_quads.
+    // The owned parts of all leaves cover the root without overlapping
+    fn query_node_with<'a, F>(&'a self, node_index: i32, node_rect: Rect, owned: Rect, query_r: Rect, f: &mut F) -> ControlFlow<()>
+    where F: FnMut(ElementId, &'a Rect, &'a T) -> ControlFlow<()> {
+
+        let node = &self.nodes[node_index];
+
+        if node.is_leaf() {
+
+            let mut elm_node_index = node.first_child;
+
+            while elm_node_index != -1 {
+                let elm_node = &self.element_nodes[elm_node_index];
+                let elm = &self.elm_rects[elm_node.elm_id];
+
+                if query_r.intersect(elm.rect) && owned.contains_point(self.anchor(elm.rect.intersection(query_r))) {
+                    f(elm_node.elm_id, &elm.rect, &self.data[elm.data_id])?;
+                }
+
+                elm_node_index = elm_node.next;
+            }
+
+            return ControlFlow::Continue(());
+        }
+
+        let locations = node_rect.location_quads();
+        let owned_quads = node_rect.owned_quads(owned);
+
+        for i in 0..4 {
+            if locations[i].intersect(query_r) {
+                self.query_node_with(node.first_child + i as i32, locations[i], owned_quads[i], query_r, f)?;
             }
         }
+
+        ControlFlow::Continue(())
     }
+}
 
-    pub fn get_leaf_elements(&mut self, node_index: i32, data_vec: &mut Vec::<i32>) {
 
-        self.ensure_query_tmp_buffer_size();
+// Nearest element
+impl<T, A: Aggregate<T>> QuadTree<T, A> {
 
+    /// Id of the element closest to point, measured to the closest point of its rect, ties go to the lowest id.
+    /// Like the other queries only elements intersecting the root are found
+    pub fn nearest(&self, point: Point) -> Option<ElementId> {
+        let mut best = None;
 
-        let leaf_node = &self.nodes[node_index];
+        self.nearest_node(0, self.root_rect, point, &mut best);
 
-        let mut elm_node_index = leaf_node.first_child;
+        best.map(|(_, element_id)| element_id)
+    }
 
-        while elm_node_index != -1 {
-            let elm_node = &self.element_nodes[elm_node_index];
-            let element_id = elm_node.elm_id;
-            let elm_rect = &self.elm_rects[element_id];
-            // Not omit and not already added to output and intersect query
-            if !self.query_tmp_buffer[element_id as usize] {
 
-                // add to found element for this query
-                self.query_tmp_buffer[element_id as usize] = true;
+    // best is (distance squared, id) of the closest element found so far
+    fn nearest_node(&self, node_index: i32, node_rect: Rect, point: Point, best: &mut Option<(i64, ElementId)>) {
 
-                data_vec.push(element_id);
+        if let Some((best_distance, _)) = *best {
+            // equal distance can still have a lower id
+            if node_rect.distance_squared(point) > best_distance {
                 return;
             }
-            elm_node_index = elm_node.next;
         }
 
-         // clear tmp buffer
-        for i in 0..self.query_tmp_buffer.len() {
-            self.query_tmp_buffer[i] = false;
+        let node = &self.nodes[node_index];
+
+        if node.is_leaf() {
+
+            let mut elm_node_index = node.first_child;
+
+            while elm_node_index != -1 {
+                let elm_node = &self.element_nodes[elm_node_index];
+                let candidate = (self.elm_rects[elm_node.elm_id].rect.distance_squared(point), elm_node.elm_id);
+
+                if best.is_none_or(|b| candidate < b) {
+                    *best = Some(candidate);
+                }
+
+                elm_node_index = elm_node.next;
+            }
+
+            return;
         }
+
+        // closest quads first, so more can be skipped
+        let locations = node_rect.location_quads();
+        let mut order = [0, 1, 2, 3];
+        order.sort_by_key(|&i| locations[i].distance_squared(point));
+
+        for i in order {
+            self.nearest_node(node.first_child + i as i32, locations[i], point, best);
+        }
+    }
+}
+
+
+// Copy out of the tree, when elements can be cloned
+impl<T: Clone, A: Aggregate<T>> QuadTree<T, A> {
+
+    pub fn query_p(&self, query_p: Point, omit_elm: i32, output: &mut Vec<T>) {
+        self.query(Point::to_rect(query_p), omit_elm, output);
+    }
+
+    pub fn query(&self, query_r: Rect, omit_elm: i32, output: &mut Vec<T>) {
+        self.query_with(query_r, |element_id, _, data| {
+            if element_id != omit_elm {
+                output.push(data.clone());
+            }
+            ControlFlow::Continue(())
+        });
     }
 }
@@ -0,0 +1,318 @@
+use crate::free_list::FreeList;
+use crate::*;
+
+
+/// Quadtree over a grid of integer cells, where each cell has a value.
+/// Quads where all cells have the same value are stored as a single leaf.
+/// Cells on the middle line of a node belong to the left and bottom child, see Rect::cell_quads
+pub struct RegionQuadTree<V> {
+
+    // Root first, laid out like the nodes of QuadTree, but a leafs first child is index into values
+    nodes: BlockList::<Node>,
+
+    // Value of each leaf
+    values: FreeList::<V>,
+
+    // Rect for the root, all cells inside it are part of the grid
+    root_rect: Rect,
+}
+
+
+// Public interface
+impl<V: Clone + PartialEq> RegionQuadTree<V> {
+
+    /// Grid covering rect where every cell has value
+    pub fn new(rect: Rect, value: V) -> Self {
+
+        let mut values = FreeList::new();
+        let nodes = BlockList::new(Node {
+            first_child: values.insert(value),
+            count: 0,
+        });
+
+        RegionQuadTree {
+            nodes,
+            values,
+            root_rect: rect,
+        }
+    }
+
+    /// Build from rows of values, grid[y][x] is the value of cell (x, y).
+    /// All rows must have the same length
+    pub fn from_grid(grid: &[Vec::<V>]) -> Self {
+        assert!(!grid.is_empty() && !grid[0].is_empty());
+        assert!(grid.iter().all(|row| row.len() == grid[0].len()));
+
+        let rect = Rect::from_points(Point::new(0, 0), Point::new(grid[0].len() as i32 - 1, grid.len() as i32 - 1));
+
+        let mut tree = RegionQuadTree::new(rect, grid[0][0].clone());
+        tree.build_node(0, rect, grid);
+        tree
+    }
+
+    pub fn root_rect(&self) -> Rect {
+        self.root_rect
+    }
+
+    /// Value of cell (x, y), None if outside the root
+    pub fn get(&self, x: i32, y: i32) -> Option::<&V> {
+        let point = Point::new(x, y);
+
+        if !self.root_rect.contains_point(point) {
+            return None;
+        }
+
+        let mut node_index = 0;
+        let mut node_rect = self.root_rect;
+
+        while self.nodes[node_index].is_branch() {
+            let quad = node_rect.cell_quad(point);
+
+            node_index = self.nodes[node_index].first_child + quad as i32;
+            node_rect = node_rect.cell_quads()[quad];
+        }
+
+        Some(&self.values[self.nodes[node_index].first_child])
+    }
+
+    /// Set all cells in rect to value. Leafs are split where rect only covers part of them,
+    /// and quads that ends up with the same value everywhere are merged again
+    pub fn set_rect(&mut self, rect: Rect, value: V) {
+        self.set_node(0, self.root_rect, rect, &value);
+    }
+
+    /// Iterate all uniform blocks as (rect, value)
+    pub fn blocks(&self) -> Blocks<'_, V> {
+        Blocks {
+            tree: self,
+            to_process: vec![(0, self.root_rect)],
+        }
+    }
+}
+
+
+pub struct Blocks<'a, V> {
+    tree: &'a RegionQuadTree<V>,
+    to_process: Vec::<(i32, Rect)>,
+}
+
+
+impl<'a, V> Iterator for Blocks<'a, V> {
+    type Item = (Rect, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+
+        while let Some((node_index, node_rect)) = self.to_process.pop() {
+            let node = &self.tree.nodes[node_index];
+
+            if node.is_leaf() {
+                if !node_rect.is_empty() {
+                    return Some((node_rect, &self.tree.values[node.first_child]));
+                }
+                continue;
+            }
+
+            // push in reverse so blocks come out in TL, TR, BL, BR order
+            let quads = node_rect.cell_quads();
+            for i in (0..4).rev() {
+                self.to_process.push((node.first_child + i as i32, quads[i]));
+            }
+        }
+
+        None
+    }
+}
+
+
+// Private functions
+impl<V: Clone + PartialEq> RegionQuadTree<V> {
+
+    // node_index is a leaf, split it if rect of the grid is not uniform
+    fn build_node(&mut self, node_index: i32, node_rect: Rect, grid: &[Vec::<V>]) {
+
+        let first = &grid[node_rect.bottom as usize][node_rect.left as usize];
+
+        let uniform = (node_rect.bottom..=node_rect.top)
+            .all(|y| grid[y as usize][node_rect.left as usize..=node_rect.right as usize].iter().all(|v| v == first));
+
+        let value_index = self.nodes[node_index].first_child;
+        self.values[value_index] = first.clone();
+
+        if uniform {
+            return;
+        }
+
+        self.split(node_index);
+
+        let first_child = self.nodes[node_index].first_child;
+        let quads = node_rect.cell_quads();
+
+        for (i, &quad) in quads.iter().enumerate() {
+            if !quad.is_empty() {
+                self.build_node(first_child + i as i32, quad, grid);
+            }
+        }
+    }
+
+
+    fn set_node(&mut self, node_index: i32, node_rect: Rect, rect: Rect, value: &V) {
+
+        if node_rect.is_empty() || !node_rect.intersect(rect) {
+            return;
+        }
+
+        if rect.contains(node_rect) {
+            self.make_leaf(node_index, value.clone());
+            return;
+        }
+
+        if self.nodes[node_index].is_leaf() {
+            if self.values[self.nodes[node_index].first_child] == *value {
+                return;
+            }
+            self.split(node_index);
+        }
+
+        let first_child = self.nodes[node_index].first_child;
+        let quads = node_rect.cell_quads();
+
+        for (i, &quad) in quads.iter().enumerate() {
+            self.set_node(first_child + i as i32, quad, rect, value);
+        }
+
+        self.try_merge(node_index, node_rect);
+    }
+
+
+    // Turn leaf into a branch with 4 leaf children, all with the leafs value
+    fn split(&mut self, node_index: i32) {
+
+        let value_index = self.nodes[node_index].first_child;
+
+        // first child keeps the value of the leaf, the others get copies
+        let mut child_value = || Node { first_child: self.values.insert(self.values[value_index].clone()), count: 0 };
+
+        let block = [Node { first_child: value_index, count: 0 }, child_value(), child_value(), child_value()];
+        let first_child = self.nodes.alloc_block(block);
+
+        self.nodes[node_index].first_child = first_child;
+        self.nodes[node_index].count = -1;
+    }
+
+
+    // Merge branch into a leaf if all its non empty children are leafs with the same value
+    fn try_merge(&mut self, node_index: i32, node_rect: Rect) {
+
+        let first_child = self.nodes[node_index].first_child;
+        let quads = node_rect.cell_quads();
+
+        let mut value_index = -1;
+
+        for (i, quad) in quads.iter().enumerate() {
+            if quad.is_empty() {
+                continue;
+            }
+
+            let child = &self.nodes[first_child + i as i32];
+
+            if child.is_branch() {
+                return;
+            }
+
+            if value_index == -1 {
+                value_index = child.first_child;
+            }
+            else if self.values[value_index] != self.values[child.first_child] {
+                return;
+            }
+        }
+
+        let value = self.values[value_index].clone();
+        self.make_leaf(node_index, value);
+    }
+
+
+    // Make node a leaf with value, freeing everything below it
+    fn make_leaf(&mut self, node_index: i32, value: V) {
+
+        if self.nodes[node_index].is_leaf() {
+            let value_index = self.nodes[node_index].first_child;
+            self.values[value_index] = value;
+            return;
+        }
+
+        self.free_children(node_index);
+
+        self.nodes[node_index].first_child = self.values.insert(value);
+        self.nodes[node_index].count = 0;
+    }
+
+
+    fn free_children(&mut self, node_index: i32) {
+
+        let first_child = self.nodes[node_index].first_child;
+
+        for i in 0..4 {
+            let child_index = first_child + i;
+            if self.nodes[child_index].is_branch() {
+                self.free_children(child_index);
+            }
+            else {
+                self.values.erase(self.nodes[child_index].first_child);
+            }
+        }
+
+        self.nodes.free_block(first_child);
+    }
+}
+
+
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn from_grid_merges_uniform() {
+
+        let mut grid = vec![vec![0; 8]; 8];
+        grid[7][7] = 1;
+
+        let rqt = RegionQuadTree::from_grid(&grid);
+
+        // 3 levels of split towards (7, 7), each leaving 3 uniform quads, and the cell itself
+        assert_eq!(rqt.blocks().count(), 10);
+
+        assert_eq!(rqt.get(7, 7), Some(&1));
+        assert_eq!(rqt.get(6, 7), Some(&0));
+        assert_eq!(rqt.get(0, 0), Some(&0));
+        assert_eq!(rqt.get(8, 0), None);
+
+        let cells: i32 = rqt.blocks().map(|(r, _)| (r.right - r.left + 1) * (r.top - r.bottom + 1)).sum();
+        assert_eq!(cells, 64);
+    }
+
+
+    #[test]
+    fn set_rect_split_and_merge() {
+
+        let rect = Rect::from_points(Point::new(0, 0), Point::new(9, 6));
+        let mut rqt = RegionQuadTree::new(rect, false);
+
+        let blocked = Rect::from_points(Point::new(2, 1), Point::new(6, 5));
+        rqt.set_rect(blocked, true);
+
+        for x in 0..10 {
+            for y in 0..7 {
+                assert_eq!(rqt.get(x, y), Some(&blocked.contains_point(Point::new(x, y))));
+            }
+        }
+
+        rqt.set_rect(blocked, false);
+
+        assert_eq!(rqt.blocks().count(), 1);
+        assert_eq!(rqt.nodes.elements_count(), 1);
+        assert_eq!(rqt.values.elements_count(), 1);
+    }
+}
@@ -0,0 +1,205 @@
+use crate::*;
+
+
+/// Operations shared by the spatial structures in this crate, so code can be written once and
+/// run on whichever fits the workload: QuadTree, UniformGrid or BruteForceIndex.
+/// All of them give out ids the same way, reusing the ids of removed elements.
+/// An element that is accepted is found by every query, a QuadTree that does not grow rejects rects outside its root
+pub trait SpatialIndex<T> {
+
+    /// Insert element, or fail as ['QuadTree::try_insert()'] does when rect does not touch ['insert_bounds()']
+    fn insert(&mut self, element: T, rect: Rect) -> Result<ElementId, QuadTreeError>;
+
+    /// Remove element and return it, None if element_id is not in the index
+    fn remove(&mut self, element_id: ElementId) -> Option<T>;
+
+    /// Move element to rect, failing like insert for rects it does not accept
+    fn update(&mut self, element_id: ElementId, rect: Rect) -> Result<(), QuadTreeError>;
+
+    /// Rects that do not touch this are rejected with OutOfBounds. None when every rect is accepted
+    fn insert_bounds(&self) -> Option<Rect> {
+        None
+    }
+
+    /// Ids of all elements intersecting rect, each once, in no particular order
+    fn query_rect(&self, rect: Rect, output: &mut Vec<ElementId>);
+
+    /// Ids of all elements containing point, each once, in no particular order
+    fn query_point(&self, point: Point, output: &mut Vec<ElementId>) {
+        self.query_rect(Point::to_rect(point), output);
+    }
+
+    /// Id of the element closest to point, measured to the closest point of its rect, ties go to the lowest id
+    fn nearest(&self, point: Point) -> Option<ElementId>;
+
+    /// All elements as (id, rect, element) in id order
+    fn elements(&self) -> Box<dyn Iterator<Item = (ElementId, Rect, &T)> + '_>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+
+impl<T, A: Aggregate<T>> SpatialIndex<T> for QuadTree<T, A> {
+
+    fn insert(&mut self, element: T, rect: Rect) -> Result<ElementId, QuadTreeError> {
+        self.try_insert(element, rect)
+    }
+
+    fn remove(&mut self, element_id: ElementId) -> Option<T> {
+        self.try_remove(element_id).ok()
+    }
+
+    fn update(&mut self, element_id: ElementId, rect: Rect) -> Result<(), QuadTreeError> {
+        self.try_update(element_id, rect)
+    }
+
+    // A growing root takes any rect, but can fail with CannotGrow near the range of i32
+    fn insert_bounds(&self) -> Option<Rect> {
+        if self.auto_grow {
+            None
+        }
+        else {
+            Some(self.root_rect)
+        }
+    }
+
+    fn query_rect(&self, rect: Rect, output: &mut Vec<ElementId>) {
+        self.query_ids(rect, -1, output);
+    }
+
+    fn nearest(&self, point: Point) -> Option<ElementId> {
+        QuadTree::nearest(self, point)
+    }
+
+    fn elements(&self) -> Box<dyn Iterator<Item = (ElementId, Rect, &T)> + '_> {
+        Box::new(self.iter())
+    }
+
+    fn len(&self) -> usize {
+        QuadTree::len(self)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    // Same operations on any index, returns what the queries found
+    fn exercise<I: SpatialIndex<i32>>(index: &mut I) -> Vec::<Vec::<ElementId>> {
+
+        let mut ids = vec![];
+
+        for i in 0..30 {
+            ids.push(index.insert(i, Rect::new((i * 37) % 200 - 100, (i * 53) % 200 - 100, i % 7, i % 5)).unwrap());
+        }
+
+        // spans many cells and leaves
+        ids.push(index.insert(100, Rect::from_points(Point::new(-60, -20), Point::new(70, 10))).unwrap());
+
+        // outside the root of the quadtrees, found if accepted
+        let far = Rect::new(300, 300, 2, 2);
+
+        match index.insert(200, far) {
+            Ok(id) => {
+                let mut found = vec![];
+                index.query_point(Point::new(301, 299), &mut found);
+                assert_eq!(found, vec![id]);
+                assert_eq!(index.nearest(Point::new(400, 400)), Some(id));
+                assert_eq!(index.remove(id), Some(200));
+            },
+            Err(err) => assert_eq!(err, QuadTreeError::OutOfBounds(far)),
+        }
+
+        match index.update(ids[6], far) {
+            Ok(()) => {
+                let mut found = vec![];
+                index.query_rect(far, &mut found);
+                assert_eq!(found, vec![ids[6]]);
+                index.update(ids[6], Rect::new((6 * 37) % 200 - 100, (6 * 53) % 200 - 100, 6, 1)).unwrap();
+            },
+            Err(err) => assert_eq!(err, QuadTreeError::OutOfBounds(far)),
+        }
+
+        assert_eq!(index.remove(ids[3]), Some(3));
+        assert_eq!(index.remove(ids[3]), None);
+        index.update(ids[5], Rect::new(0, 0, 3, 3)).unwrap();
+        assert_eq!(index.update(-1, Rect::new(0, 0, 3, 3)), Err(QuadTreeError::InvalidElement(-1)));
+
+        let mut results = vec![];
+
+        for &query_r in &[Rect::new(-100, 100, 200, 200), Rect::new(-20, 20, 30, 30), Rect::new(50, -50, 40, 40)] {
+            let mut found = vec![];
+            index.query_rect(query_r, &mut found);
+            found.sort();
+            results.push(found);
+        }
+
+        let mut found = vec![];
+        index.query_point(Point::new(1, -1), &mut found);
+        found.sort();
+        results.push(found);
+
+        for &(x, y) in &[(0, 0), (90, 90), (-95, 40), (200, -200)] {
+            results.push(index.nearest(Point::new(x, y)).into_iter().collect());
+        }
+
+        results.push(index.elements().map(|(id, _, _)| id).collect());
+
+        assert_eq!(index.len(), 30);
+
+        results
+    }
+
+
+    #[test]
+    fn backends_agree() {
+
+        let rect = Rect::from_points(Point {x: -128, y: -128}, Point { x: 128, y: 128} );
+
+        let mut qt = QuadTree::new(rect);
+        qt.set_elements_per_node(3);
+
+        let mut growing = QuadTree::new(rect);
+        growing.set_elements_per_node(3);
+        growing.set_auto_grow(true);
+
+        let expected = exercise(&mut BruteForceIndex::new());
+
+        assert_eq!(exercise(&mut qt), expected);
+        assert_eq!(exercise(&mut growing), expected);
+        assert_eq!(exercise(&mut UniformGrid::new(rect, 16)), expected);
+        assert_eq!(exercise(&mut UniformGrid::new(Rect::new(0, 0, 10, 10), 4)), expected);
+    }
+
+
+    #[test]
+    fn quadtree_rejects_what_it_cannot_find() {
+
+        let rect = Rect::from_points(Point {x: -128, y: -128}, Point { x: 128, y: 128} );
+        let far = Rect::new(300, 300, 2, 2);
+
+        let mut qt = QuadTree::new(rect);
+        let id = SpatialIndex::insert(&mut qt, 1, Rect::new(0, 0, 1, 1)).unwrap();
+
+        assert_eq!(SpatialIndex::insert(&mut qt, 2, far), Err(QuadTreeError::OutOfBounds(far)));
+        assert_eq!(SpatialIndex::update(&mut qt, id, far), Err(QuadTreeError::OutOfBounds(far)));
+        assert_eq!(qt.insert_bounds(), Some(rect));
+        assert_eq!(qt.rect(id), Some(Rect::new(0, 0, 1, 1)));
+        assert_eq!(SpatialIndex::len(&qt), 1);
+
+        // touching the root is enough
+        assert!(SpatialIndex::insert(&mut qt, 3, Rect::new(120, 140, 20, 20)).is_ok());
+
+        qt.set_auto_grow(true);
+        assert_eq!(qt.insert_bounds(), None);
+        assert!(SpatialIndex::insert(&mut qt, 4, far).is_ok());
+        assert!(BruteForceIndex::<i32>::new().insert_bounds().is_none());
+        assert!(UniformGrid::<i32>::new(rect, 16).insert_bounds().is_none());
+    }
+}
@@ -0,0 +1,673 @@
+//! Differential testing of SpatialIndex implementations against BruteForceIndex.
+//! Random operation sequences are run on both, and the first place they disagree is
+//! reported with the shortest sequence found that still disagrees
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use crate::free_list::Remap;
+use crate::*;
+
+
+/// One step of a test. Elements are picked by their position among the live elements in
+/// insertion order, modulo the number of live elements, so any subsequence is still a valid test
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Insert(Rect),
+    InsertDynamic(Rect, i32),
+    Remove(usize),
+    Update(usize, Rect),
+    QueryRect(Rect),
+    QueryPoint(Point),
+    Nearest(Point),
+    CountIn(Rect),
+    DrainIn(Rect),
+    RemoveIn(Rect),
+    /// Remove the elements whose value, the index of the op inserting it, is a multiple of this
+    Retain(usize),
+    Cleanup,
+    MaintainSteps(usize),
+    Compact,
+    ShrinkToFit,
+}
+
+
+/// The operations of QuadTree that are not in SpatialIndex, so the harness can run them on every index.
+/// The defaults use SpatialIndex, and do nothing for the ones that only change the structure of a QuadTree
+pub trait TreeOps<T>: SpatialIndex<T> {
+
+    fn insert_dynamic(&mut self, element: T, rect: Rect, _margin: i32) -> Result<ElementId, QuadTreeError> {
+        self.insert(element, rect)
+    }
+
+    fn drain_in(&mut self, rect: Rect) -> Vec::<(ElementId, Rect, T)> {
+        let mut ids = vec![];
+        self.query_rect(rect, &mut ids);
+
+        let rects: HashMap::<ElementId, Rect> = self.elements().map(|(element_id, rect, _)| (element_id, rect)).collect();
+
+        ids.into_iter()
+            .filter_map(|element_id| self.remove(element_id).map(|element| (element_id, rects[&element_id], element)))
+            .collect()
+    }
+
+    fn remove_in(&mut self, rect: Rect) -> usize {
+        self.drain_in(rect).len()
+    }
+
+    fn retain(&mut self, f: &mut dyn FnMut(ElementId, &Rect, &T) -> bool) -> usize {
+        let removed: Vec::<ElementId> = self.elements()
+            .filter(|&(element_id, rect, element)| !f(element_id, &rect, element))
+            .map(|(element_id, _, _)| element_id)
+            .collect();
+
+        for &element_id in &removed {
+            self.remove(element_id);
+        }
+
+        removed.len()
+    }
+
+    fn count_in(&self, rect: Rect) -> usize {
+        let mut ids = vec![];
+        self.query_rect(rect, &mut ids);
+        ids.len()
+    }
+
+    fn cleanup(&mut self) {}
+
+    fn maintain_steps(&mut self, _n: usize) -> usize {
+        0
+    }
+
+    /// New ids of the elements, None when ids do not change
+    fn compact(&mut self) -> Option<Remap> {
+        None
+    }
+
+    fn shrink_to_fit(&mut self) {}
+}
+
+
+impl<T, A: Aggregate<T>> TreeOps<T> for QuadTree<T, A> {
+
+    fn insert_dynamic(&mut self, element: T, rect: Rect, margin: i32) -> Result<ElementId, QuadTreeError> {
+        self.check_rect(rect)?;
+
+        Ok(QuadTree::insert_dynamic(self, element, rect, margin))
+    }
+
+    fn drain_in(&mut self, rect: Rect) -> Vec::<(ElementId, Rect, T)> {
+        QuadTree::drain_in(self, rect).collect()
+    }
+
+    fn remove_in(&mut self, rect: Rect) -> usize {
+        QuadTree::remove_in(self, rect)
+    }
+
+    fn retain(&mut self, f: &mut dyn FnMut(ElementId, &Rect, &T) -> bool) -> usize {
+        QuadTree::retain(self, |element_id, rect, element| f(element_id, rect, element))
+    }
+
+    fn count_in(&self, rect: Rect) -> usize {
+        QuadTree::count_in(self, rect)
+    }
+
+    fn cleanup(&mut self) {
+        QuadTree::cleanup(self)
+    }
+
+    fn maintain_steps(&mut self, n: usize) -> usize {
+        QuadTree::maintain_steps(self, n)
+    }
+
+    fn compact(&mut self) -> Option<Remap> {
+        Some(QuadTree::compact(self))
+    }
+
+    fn shrink_to_fit(&mut self) {
+        QuadTree::shrink_to_fit(self)
+    }
+}
+
+
+impl<T> TreeOps<T> for BruteForceIndex<T> {
+
+    fn insert_dynamic(&mut self, element: T, rect: Rect, margin: i32) -> Result<ElementId, QuadTreeError> {
+        Ok(BruteForceIndex::insert_dynamic(self, element, rect, margin))
+    }
+
+    fn drain_in(&mut self, rect: Rect) -> Vec::<(ElementId, Rect, T)> {
+        BruteForceIndex::drain_in(self, rect).collect()
+    }
+
+    fn remove_in(&mut self, rect: Rect) -> usize {
+        BruteForceIndex::remove_in(self, rect)
+    }
+
+    fn retain(&mut self, f: &mut dyn FnMut(ElementId, &Rect, &T) -> bool) -> usize {
+        BruteForceIndex::retain(self, |element_id, rect, element| f(element_id, rect, element))
+    }
+
+    fn count_in(&self, rect: Rect) -> usize {
+        BruteForceIndex::count_in(self, rect)
+    }
+
+    fn cleanup(&mut self) {
+        BruteForceIndex::cleanup(self)
+    }
+
+    fn maintain_steps(&mut self, n: usize) -> usize {
+        BruteForceIndex::maintain_steps(self, n)
+    }
+
+    fn compact(&mut self) -> Option<Remap> {
+        Some(BruteForceIndex::compact(self))
+    }
+
+    fn shrink_to_fit(&mut self) {
+        BruteForceIndex::shrink_to_fit(self)
+    }
+}
+
+
+impl<T> TreeOps<T> for UniformGrid<T> {}
+
+
+/// Where an index disagreed with BruteForceIndex
+#[derive(Debug, Clone)]
+pub struct Divergence {
+    /// Operations to reproduce it, the last one is where it disagrees
+    pub ops: Vec::<Op>,
+    pub expected: String,
+    pub actual: String,
+}
+
+
+impl fmt::Display for Divergence {
+
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Diverged after {} operations:", self.ops.len())?;
+
+        for op in &self.ops {
+            writeln!(f, "    {:?}", op)?;
+        }
+
+        write!(f, "expected {}, got {}", self.expected, self.actual)
+    }
+}
+
+
+/// count random operations with rects mostly inside bounds, the same for the same seed.
+/// Some are moved up to the size of bounds away, partly or all outside it
+pub fn random_ops(seed: u64, count: usize, bounds: Rect) -> Vec::<Op> {
+
+    let mut state = seed;
+    let mut next = |max: i64| -> i64 {
+        // 64 bit lcg from Knuth
+        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        ((state >> 33) % max.max(1) as u64) as i64
+    };
+
+    let width = (bounds.right - bounds.left) as i64 + 1;
+    let height = (bounds.top - bounds.bottom) as i64 + 1;
+
+    let mut random_point = || {
+        Point::new(bounds.left + next(width) as i32, bounds.bottom + next(height) as i32)
+    };
+
+    let mut ops = Vec::with_capacity(count);
+
+    for _ in 0..count {
+        let corner = random_point();
+        let size = random_point();
+
+        // mostly small rects, some up to the size of bounds
+        let rect = Rect::from_points(corner, Point::new(
+            i32::min(bounds.right, corner.x + (size.x - bounds.left) / 8),
+            i32::max(bounds.bottom, corner.y - (size.y - bounds.bottom) / 8)));
+
+        let offset = random_point();
+
+        // about every 8th rect moves by -2 to 2 times half the size of bounds in each direction
+        let (dx, dy) = if offset.x.rem_euclid(8) == 0 {
+            ((width / 2) as i32 * (offset.y.rem_euclid(5) - 2), (height / 2) as i32 * ((offset.y / 5).rem_euclid(5) - 2))
+        }
+        else {
+            (0, 0)
+        };
+
+        let corner = Point::new(corner.x + dx, corner.y + dy);
+        let rect = Rect::from_points(Point::new(rect.left + dx, rect.top + dy), Point::new(rect.right + dx, rect.bottom + dy));
+
+        let k = random_point().x.unsigned_abs() as usize;
+
+        ops.push(match random_point().x.rem_euclid(20) {
+            0..=6 => Op::Insert(rect),
+            7 => Op::InsertDynamic(rect, (k % 8) as i32),
+            8 | 9 => Op::Remove(k),
+            10 | 11 => Op::Update(k, rect),
+            12 | 13 => Op::QueryRect(rect),
+            14 => Op::QueryPoint(corner),
+            15 => Op::Nearest(corner),
+            16 => Op::CountIn(rect),
+            17 => match k % 3 {
+                0 => Op::DrainIn(rect),
+                1 => Op::RemoveIn(rect),
+                _ => Op::Retain((k / 3) % 3 + 2),
+            },
+            18 => if k.is_multiple_of(2) { Op::Cleanup } else { Op::MaintainSteps(k % 64 + 1) },
+            _ => if k.is_multiple_of(2) { Op::Compact } else { Op::ShrinkToFit },
+        });
+    }
+
+    ops
+}
+
+
+/// Run ops on a new index from make and on a BruteForceIndex, Err at the first difference
+pub fn run_ops<I, F>(make: &F, ops: &[Op]) -> Result<(), Divergence> where I: TreeOps<usize>, F: Fn() -> I {
+
+    let mut expected = BruteForceIndex::new();
+    let mut actual = make();
+
+    // (expected id, actual id) of live elements in insertion order
+    let mut live: Vec::<(ElementId, ElementId)> = vec![];
+    let mut rects: HashMap::<ElementId, Rect> = HashMap::new();
+
+    let diverged = |i: usize, expected: String, actual: String| Divergence {
+        ops: ops[..=i].to_vec(),
+        expected,
+        actual,
+    };
+
+    for (i, &op) in ops.iter().enumerate() {
+
+        let (e, a) = match op {
+            Op::Insert(rect) => {
+                let expected_id = if rejects(&actual, rect) { Err(QuadTreeError::OutOfBounds(rect)) } else { expected.insert(i, rect) };
+                let actual_id = actual.insert(i, rect);
+
+                inserted(&mut live, &mut rects, rect, expected_id, actual_id)
+            },
+            Op::InsertDynamic(rect, margin) => {
+                let expected_id = if rejects(&actual, rect) { Err(QuadTreeError::OutOfBounds(rect)) } else { TreeOps::insert_dynamic(&mut expected, i, rect, margin) };
+                let actual_id = actual.insert_dynamic(i, rect, margin);
+
+                inserted(&mut live, &mut rects, rect, expected_id, actual_id)
+            },
+            Op::Remove(_) | Op::Update(..) if live.is_empty() => (String::new(), String::new()),
+            Op::Remove(k) => {
+                let (expected_id, actual_id) = live.remove(k % live.len());
+                rects.remove(&expected_id);
+
+                (format!("{:?}", expected.remove(expected_id)), format!("{:?}", actual.remove(actual_id)))
+            },
+            Op::Update(k, rect) => {
+                let (expected_id, actual_id) = live[k % live.len()];
+
+                let e = if rejects(&actual, rect) { Err(QuadTreeError::OutOfBounds(rect)) } else { expected.update(expected_id, rect) };
+                let a = actual.update(actual_id, rect);
+
+                if e.is_ok() {
+                    rects.insert(expected_id, rect);
+                }
+
+                (format!("{:?}", e), format!("{:?}", a))
+            },
+            Op::QueryRect(rect) => (found(&expected, &live, rect, false), found(&actual, &live, rect, true)),
+            Op::QueryPoint(point) => {
+                let rect = Point::to_rect(point);
+                (found(&expected, &live, rect, false), found(&actual, &live, rect, true))
+            },
+            Op::Nearest(point) => {
+                // compare distance, ids of elements at the same distance can differ between indices
+                let distance = |id: Option<ElementId>, by_actual: bool| {
+                    id.and_then(|id| live.iter().find(|&&(e, a)| if by_actual { a == id } else { e == id }))
+                        .map(|&(e, _)| rects[&e].distance_squared(point))
+                };
+
+                (format!("{:?}", distance(expected.nearest(point), false)), format!("{:?}", distance(actual.nearest(point), true)))
+            },
+            Op::CountIn(rect) => (expected.count_in(rect).to_string(), actual.count_in(rect).to_string()),
+            Op::DrainIn(rect) => {
+                let e = drained(&live, TreeOps::drain_in(&mut expected, rect), false);
+                let a = drained(&live, actual.drain_in(rect), true);
+
+                forget_removed(&expected, &actual, &mut live, &mut rects, e, a)
+            },
+            Op::RemoveIn(rect) => {
+                let e = expected.remove_in(rect).to_string();
+                let a = actual.remove_in(rect).to_string();
+
+                forget_removed(&expected, &actual, &mut live, &mut rects, e, a)
+            },
+            Op::Retain(m) => {
+                let e = TreeOps::retain(&mut expected, &mut |_, _, &value| value % m != 0).to_string();
+                let a = actual.retain(&mut |_, _, &value| value % m != 0).to_string();
+
+                forget_removed(&expected, &actual, &mut live, &mut rects, e, a)
+            },
+            Op::Cleanup => {
+                expected.cleanup();
+                actual.cleanup();
+                (String::new(), String::new())
+            },
+            Op::MaintainSteps(n) => {
+                // merges are not compared, only that the elements are still found after
+                expected.maintain_steps(n);
+                actual.maintain_steps(n);
+                (String::new(), String::new())
+            },
+            Op::ShrinkToFit => {
+                expected.shrink_to_fit();
+                actual.shrink_to_fit();
+                (String::new(), String::new())
+            },
+            Op::Compact => {
+                let expected_remap = TreeOps::compact(&mut expected);
+                let actual_remap = actual.compact();
+
+                let new_id = |remap: &Option<Remap>, id: ElementId| remap.as_ref().map_or(Some(id), |remap| remap.get(id));
+
+                let moved: Vec::<(Option<ElementId>, Option<ElementId>)> = live.iter()
+                    .map(|&(e, a)| (new_id(&expected_remap, e), new_id(&actual_remap, a)))
+                    .collect();
+
+                let lost = |by_actual: bool| format!("lost {:?}", moved.iter()
+                    .enumerate()
+                    .filter(|(_, &(e, a))| if by_actual { a.is_none() } else { e.is_none() })
+                    .map(|(position, _)| position)
+                    .collect::<Vec::<usize>>());
+
+                let res = (lost(false), lost(true));
+
+                rects = live.iter().zip(&moved)
+                    .filter_map(|(&(e, _), &(new_e, _))| new_e.map(|new_e| (new_e, rects[&e])))
+                    .collect();
+
+                live = moved.into_iter()
+                    .filter_map(|ids| match ids {
+                        (Some(e), Some(a)) => Some((e, a)),
+                        _ => None,
+                    })
+                    .collect();
+
+                res
+            },
+        };
+
+        if e != a {
+            return Err(diverged(i, e, a));
+        }
+
+        if expected.len() != actual.len() {
+            return Err(diverged(i, format!("len {}", expected.len()), format!("len {}", actual.len())));
+        }
+    }
+
+    Ok(())
+}
+
+
+// True if index rejects rect, then the reference rejects it too
+fn rejects<I: SpatialIndex<usize>>(index: &I, rect: Rect) -> bool {
+    index.insert_bounds().is_some_and(|bounds| !bounds.intersect(rect))
+}
+
+
+// Keep track of an element both indices accepted, or compare how they failed
+fn inserted(live: &mut Vec::<(ElementId, ElementId)>, rects: &mut HashMap::<ElementId, Rect>, rect: Rect,
+            e: Result<ElementId, QuadTreeError>, a: Result<ElementId, QuadTreeError>) -> (String, String) {
+
+    match (e, a) {
+        (Ok(expected_id), Ok(actual_id)) => {
+            live.push((expected_id, actual_id));
+            rects.insert(expected_id, rect);
+            (String::new(), String::new())
+        },
+        (e, a) => (format!("{:?}", e.map(|_| ())), format!("{:?}", a.map(|_| ()))),
+    }
+}
+
+
+// Positions in live of the elements drained, with their rects and values
+fn drained(live: &[(ElementId, ElementId)], drained: Vec::<(ElementId, Rect, usize)>, by_actual: bool) -> String {
+
+    let mut res: Vec::<(Option<usize>, Rect, usize)> = drained.into_iter()
+        .map(|(id, rect, value)| (live.iter().position(|&(e, a)| if by_actual { a == id } else { e == id }), rect, value))
+        .collect();
+
+    res.sort_by_key(|&(position, _, _)| position);
+
+    format!("{:?}", res)
+}
+
+
+// After removing more than one element, compare which are left and drop the rest from live.
+// e and a are what each index returned for the removal
+fn forget_removed<I: SpatialIndex<usize>>(expected: &BruteForceIndex<usize>, actual: &I, live: &mut Vec::<(ElementId, ElementId)>,
+                                          rects: &mut HashMap::<ElementId, Rect>, e: String, a: String) -> (String, String) {
+
+    let expected_ids: HashSet::<ElementId> = expected.elements().map(|(id, _, _)| id).collect();
+    let actual_ids: HashSet::<ElementId> = actual.elements().map(|(id, _, _)| id).collect();
+
+    let left = |ids: &HashSet::<ElementId>, by_actual: bool| -> Vec::<usize> {
+        live.iter()
+            .enumerate()
+            .filter(|(_, &(e, a))| ids.contains(if by_actual { &a } else { &e }))
+            .map(|(position, _)| position)
+            .collect()
+    };
+
+    let res = (format!("{} left {:?}", e, left(&expected_ids, false)), format!("{} left {:?}", a, left(&actual_ids, true)));
+
+    live.retain(|(e, _)| expected_ids.contains(e));
+    rects.retain(|e, _| expected_ids.contains(e));
+
+    res
+}
+
+
+// Positions in live of the elements found, sorted, so indices with different ids can be compared
+fn found<I: SpatialIndex<usize>>(index: &I, live: &[(ElementId, ElementId)], rect: Rect, by_actual: bool) -> String {
+
+    let mut ids = vec![];
+    index.query_rect(rect, &mut ids);
+
+    let mut positions: Vec::<Option<usize>> = ids.iter()
+        .map(|&id| live.iter().position(|&(e, a)| if by_actual { a == id } else { e == id }))
+        .collect();
+
+    positions.sort();
+
+    format!("{:?}", positions)
+}
+
+
+/// Run count random operations from seed, and if they diverge make the sequence as short as possible
+pub fn check<I, F>(make: F, seed: u64, count: usize, bounds: Rect) -> Result<(), Divergence> where I: TreeOps<usize>, F: Fn() -> I {
+
+    let ops = random_ops(seed, count, bounds);
+
+    match run_ops(&make, &ops) {
+        Ok(()) => Ok(()),
+        Err(divergence) => Err(minimize(&make, divergence)),
+    }
+}
+
+
+// Remove chunks of operations while it still diverges, halving the chunk size when nothing can be removed
+fn minimize<I, F>(make: &F, mut divergence: Divergence) -> Divergence where I: TreeOps<usize>, F: Fn() -> I {
+
+    let mut chunk = usize::max(1, divergence.ops.len() / 2);
+
+    loop {
+        let mut removed_any = false;
+        let mut start = 0;
+
+        while start < divergence.ops.len() {
+            let mut candidate = divergence.ops.clone();
+            candidate.drain(start..usize::min(start + chunk, candidate.len()));
+
+            match run_ops(make, &candidate) {
+                Err(shorter) => {
+                    divergence = shorter;
+                    removed_any = true;
+                },
+                Ok(()) => start += chunk,
+            }
+        }
+
+        if !removed_any {
+            if chunk == 1 {
+                return divergence;
+            }
+            chunk /= 2;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    fn bounds() -> Rect {
+        Rect::from_points(Point {x: -128, y: -128}, Point { x: 128, y: 128} )
+    }
+
+
+    #[test]
+    fn quadtree_matches_brute_force() {
+
+        for seed in 0..20 {
+            for &elements_per_node in &[4, 16, 300] {
+                let make = || {
+                    let mut qt = QuadTree::new(bounds());
+                    qt.set_elements_per_node(elements_per_node);
+                    qt
+                };
+
+                if let Err(divergence) = check(make, seed, 300, bounds()) {
+                    panic!("seed {} elements_per_node {}\n{}", seed, elements_per_node, divergence);
+                }
+            }
+        }
+    }
+
+
+    #[test]
+    fn growing_quadtree_matches_brute_force() {
+
+        for seed in 0..20 {
+            for &elements_per_node in &[8, 16] {
+                let make = || {
+                    let mut qt = QuadTree::new(Rect::new(-8, 8, 16, 16));
+                    qt.set_elements_per_node(elements_per_node);
+                    qt.set_auto_grow(true);
+                    qt
+                };
+
+                if let Err(divergence) = check(make, seed, 300, bounds()) {
+                    panic!("seed {} elements_per_node {}\n{}", seed, elements_per_node, divergence);
+                }
+            }
+        }
+    }
+
+
+    #[test]
+    fn random_ops_cover_outside_bounds() {
+
+        let ops: Vec::<Op> = (0..20).flat_map(|seed| random_ops(seed, 300, bounds())).collect();
+
+        let retained: HashSet::<usize> = ops.iter()
+            .filter_map(|op| match *op {
+                Op::Retain(m) => Some(m),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(retained, [2, 3, 4].iter().copied().collect());
+
+        let inserted: Vec::<Rect> = ops.iter()
+            .filter_map(|op| match *op {
+                Op::Insert(rect) | Op::InsertDynamic(rect, _) => Some(rect),
+                _ => None,
+            })
+            .collect();
+
+        assert!(inserted.iter().any(|&rect| !bounds().intersect(rect)));
+        assert!(inserted.iter().any(|&rect| bounds().intersect(rect) && !bounds().contains(rect)));
+        assert!(ops.contains(&Op::ShrinkToFit));
+    }
+
+
+    #[test]
+    fn grid_matches_brute_force() {
+        for seed in 0..20 {
+            if let Err(divergence) = check(|| UniformGrid::new(Rect::new(-50, 50, 100, 100), 16), seed, 300, bounds()) {
+                panic!("seed {}\n{}", seed, divergence);
+            }
+        }
+    }
+
+
+    // Loses the third element inserted
+    struct Forgetful(BruteForceIndex<usize>, usize);
+
+    impl SpatialIndex<usize> for Forgetful {
+        fn insert(&mut self, element: usize, rect: Rect) -> Result<ElementId, QuadTreeError> {
+            self.1 += 1;
+            let id = self.0.insert(element, rect)?;
+            if self.1 == 3 {
+                self.0.remove(id);
+                self.0.insert(element, Rect::new(1000, 1000, 0, 0))?;
+            }
+            Ok(id)
+        }
+
+        fn remove(&mut self, element_id: ElementId) -> Option<usize> {
+            self.0.remove(element_id)
+        }
+
+        fn update(&mut self, element_id: ElementId, rect: Rect) -> Result<(), QuadTreeError> {
+            self.0.update(element_id, rect)
+        }
+
+        fn query_rect(&self, rect: Rect, output: &mut Vec<ElementId>) {
+            self.0.query_rect(rect, output)
+        }
+
+        fn nearest(&self, point: Point) -> Option<ElementId> {
+            self.0.nearest(point)
+        }
+
+        fn elements(&self) -> Box<dyn Iterator<Item = (ElementId, Rect, &usize)> + '_> {
+            self.0.elements()
+        }
+
+        fn len(&self) -> usize {
+            self.0.len()
+        }
+    }
+
+    impl TreeOps<usize> for Forgetful {}
+
+
+    #[test]
+    fn divergence_is_minimized() {
+
+        let divergence = (0..20)
+            .find_map(|seed| check(|| Forgetful(BruteForceIndex::new(), 0), seed, 200, bounds()).err())
+            .expect("should diverge");
+
+        // three inserts and the query that misses the last, unless it was removed or updated before
+        let inserts = divergence.ops.iter().filter(|op| matches!(op, Op::Insert(_) | Op::InsertDynamic(..))).count();
+        assert_eq!(inserts, 3, "{}", divergence);
+        assert!(divergence.ops.len() <= 5, "{}", divergence);
+        assert!(run_ops(&|| Forgetful(BruteForceIndex::new(), 0), &divergence.ops).is_err());
+        assert!(divergence.to_string().starts_with("Diverged after"));
+    }
+}
@@ -0,0 +1,308 @@
+use std::ops::Deref;
+use crate::*;
+
+
+/// Changes to a QuadTree that are all undone if the transaction fails, see ['QuadTree::transaction()'].
+/// Derefs to the tree, so it can be queried while changing it
+pub struct Transaction<'a, T, A: Aggregate<T> = ()> {
+    tree: &'a mut QuadTree<T, A>,
+
+    // Taken when the transaction commits, if still here on drop the tree is rolled back.
+    // So a panic in f does not leave the lists recording
+    saved: Option<Saved>,
+}
+
+
+// Fields of the tree that are not journaled. The lists and aggregates record their own changes
+struct Saved {
+    root_rect: Rect,
+    max_depth: i32,
+    // The root only grows during a transaction, so growth is undone by truncating
+    grown_len: usize,
+    maintain_cursor: i32,
+    maintain_changed: bool,
+    maintain_idle: bool,
+}
+
+
+impl<T, A: Aggregate<T>> QuadTree<T, A> {
+
+    /// Run f with a transaction on the tree. If f returns an error, or panics, every change made through the transaction is undone,
+    /// node splits, root growth, element links and which ids are reused next, and the error is returned.
+    /// Every list in the tree records how to undo its changes while f runs, so a transaction costs time and memory
+    /// in proportion to the changes made, not to the whole tree. Removed elements are dropped when f returns Ok
+    pub fn transaction<F, R, E>(&mut self, f: F) -> Result<R, E> where F: FnOnce(&mut Transaction<'_, T, A>) -> Result<R, E> {
+
+        let saved = Saved {
+            root_rect: self.root_rect,
+            max_depth: self.max_depth,
+            grown_len: self.grown_from.len(),
+            maintain_cursor: self.maintain_cursor,
+            maintain_changed: self.maintain_changed,
+            maintain_idle: self.maintain_idle,
+        };
+
+        self.elm_rects.begin_with_writes();
+        self.element_nodes.begin_with_writes();
+        self.nodes.begin();
+        self.data.begin();
+        self.begin_aggregates();
+        self.hold_events();
+
+        let mut tx = Transaction { tree: self, saved: Some(saved) };
+
+        let res = f(&mut tx);
+
+        if res.is_ok() {
+            tx.saved = None;
+
+            let tree = &mut *tx.tree;
+
+            tree.elm_rects.commit();
+            tree.element_nodes.commit();
+            tree.nodes.commit();
+            tree.data.commit();
+            tree.commit_aggregates();
+            tree.release_events();
+        }
+
+        // rolls back on drop if not committed
+        res
+    }
+}
+
+
+impl<'a, T, A: Aggregate<T>> Drop for Transaction<'a, T, A> {
+
+    fn drop(&mut self) {
+
+        let saved = match self.saved.take() {
+            Some(saved) => saved,
+            None => return,
+        };
+
+        let tree = &mut *self.tree;
+
+        tree.elm_rects.rollback();
+        tree.element_nodes.rollback();
+        tree.nodes.rollback();
+        tree.data.rollback();
+        tree.rollback_aggregates();
+        tree.drop_events();
+
+        tree.root_rect = saved.root_rect;
+        tree.max_depth = saved.max_depth;
+        tree.grown_from.truncate(saved.grown_len);
+        tree.maintain_cursor = saved.maintain_cursor;
+        tree.maintain_changed = saved.maintain_changed;
+        tree.maintain_idle = saved.maintain_idle;
+    }
+}
+
+
+impl<'a, T, A: Aggregate<T>> Transaction<'a, T, A> {
+
+    pub fn insert(&mut self, element: T, element_rect: Rect) -> ElementId {
+        self.tree.insert(element, element_rect)
+    }
+
+    pub fn try_insert(&mut self, element: T, element_rect: Rect) -> Result<ElementId, QuadTreeError> {
+        self.tree.try_insert(element, element_rect)
+    }
+
+    pub fn update(&mut self, element_id: ElementId, element_rect: Rect) {
+        self.tree.update(element_id, element_rect);
+    }
+
+    pub fn try_update(&mut self, element_id: ElementId, element_rect: Rect) -> Result<(), QuadTreeError> {
+        self.tree.try_update(element_id, element_rect)
+    }
+
+    /// Remove element from the tree. The element is kept until the transaction is done, so it can be put back
+    pub fn remove(&mut self, element_id: ElementId) {
+        let tree = &mut *self.tree;
+
+        tree.unlink(element_id);
+        tree.aggregate_unlinked(tree.elm_rects[element_id].rect);
+
+        tree.data.erase(tree.elm_rects[element_id].data_id);
+        tree.elm_rects.erase(element_id);
+    }
+
+    pub fn try_remove(&mut self, element_id: ElementId) -> Result<(), QuadTreeError> {
+        self.tree.check_element(element_id)?;
+
+        self.remove(element_id);
+        Ok(())
+    }
+}
+
+
+impl<'a, T, A: Aggregate<T>> Deref for Transaction<'a, T, A> {
+    type Target = QuadTree<T, A>;
+
+    fn deref(&self) -> &QuadTree<T, A> {
+        self.tree
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn rollback_restores_structure() {
+
+        let rect = Rect::from_points(Point {x: 0, y: 0}, Point { x: 64, y: 64} );
+
+        let mut qt = QuadTree::new(rect);
+
+        qt.set_elements_per_node(2);
+        qt.set_auto_grow(true);
+
+        let ids: Vec::<ElementId> = (0..6).map(|i| qt.insert(i, Rect::new(i * 10, 50, 2, 2))).collect();
+        qt.remove(ids[2]);
+
+        let before = format!("{:?}", qt);
+
+        let res: Result<(), QuadTreeError> = qt.transaction(|tx| {
+            for i in 0..10 {
+                tx.insert(100 + i, Rect::new(i * 3, 10, 1, 1));
+            }
+
+            // grows the root
+            tx.insert(200, Rect::new(-40, 10, 1, 1));
+
+            tx.remove(ids[0]);
+            tx.update(ids[1], Rect::new(5, 5, 1, 1));
+
+            let mut found = vec![];
+            tx.query_ids(Rect::new(0, 12, 30, 5), -1, &mut found);
+            assert_eq!(found.len(), 10);
+
+            tx.try_remove(1000)
+        });
+
+        assert_eq!(res, Err(QuadTreeError::InvalidElement(1000)));
+
+        assert_eq!(format!("{:?}", qt), before);
+        assert_eq!(qt.root_rect(), rect);
+        assert_eq!(qt.len(), 5);
+        assert_eq!(qt.count_in(rect), 5);
+        assert_eq!(qt.get(ids[0]), Some(&0));
+        assert_eq!(qt.rect(ids[1]), Some(Rect::new(10, 50, 2, 2)));
+
+        // the slot freed before the transaction is the next one used again
+        assert_eq!(qt.insert(7, Rect::new(1, 1, 1, 1)), ids[2]);
+
+        let id = qt.transaction(|tx| {
+            tx.remove(ids[0]);
+            Ok::<_, QuadTreeError>(tx.insert(8, Rect::new(2, 2, 1, 1)))
+        }).unwrap();
+
+        assert_eq!(id, ids[0]);
+        assert_eq!(qt.get(id), Some(&8));
+        assert_eq!(qt.len(), 6);
+    }
+
+
+    #[test]
+    fn panic_restores_tree() {
+
+        let rect = Rect::from_points(Point {x: 0, y: 0}, Point { x: 64, y: 64} );
+
+        let mut qt = QuadTree::new(rect);
+
+        qt.set_elements_per_node(2);
+
+        let ids: Vec::<ElementId> = (0..4).map(|i| qt.insert(i, Rect::new(i * 10, 50, 2, 2))).collect();
+
+        let before = format!("{:?}", qt);
+
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _: Result<(), QuadTreeError> = qt.transaction(|tx| {
+                tx.remove(ids[0]);
+                tx.insert(10, Rect::new(30, 30, 1, 1));
+                panic!("in transaction");
+            });
+        }));
+
+        assert!(res.is_err());
+        assert_eq!(format!("{:?}", qt), before);
+        assert_eq!(qt.get(ids[0]), Some(&0));
+
+        // data is no longer recording, so removes work
+        qt.remove(ids[0]);
+        qt.remove(ids[1]);
+        assert_eq!(qt.len(), 2);
+    }
+
+
+    #[test]
+    fn rollback_restores_aggregates_and_maintain() {
+
+        let rect = Rect::from_points(Point {x: 0, y: 0}, Point { x: 64, y: 64} );
+
+        let mut qt = QuadTree::<i32, Sum>::with_aggregate(rect);
+
+        qt.set_elements_per_node(2);
+        qt.set_auto_grow(true);
+
+        let ids: Vec::<ElementId> = (0..8).map(|i| qt.insert(i, Rect::new(i * 8, 60 - i * 4, 2, 2))).collect();
+        qt.remove(ids[3]);
+        qt.maintain_steps(3);
+
+        let before = format!("{:?}", qt);
+        let cursor = qt.maintain_cursor;
+        let changed = qt.maintain_changed;
+        let idle = qt.maintain_idle;
+        let slots = qt.node_aggregates.len();
+
+        let res: Result<(), QuadTreeError> = qt.transaction(|tx| {
+            for i in 0..10 {
+                tx.insert(100 + i, Rect::new(i * 3, 10, 1, 1));
+            }
+
+            tx.insert(200, Rect::new(100, 10, 1, 1));
+            tx.remove(ids[0]);
+            tx.update(ids[1], Rect::new(5, 5, 1, 1));
+
+            assert_eq!(tx.aggregate_in(tx.root_rect()), Sum(1 + 2 + 4 + 5 + 6 + 7 + 1045 + 200));
+
+            Err(QuadTreeError::InvalidElement(-1))
+        });
+
+        assert!(res.is_err());
+        assert_eq!(format!("{:?}", qt), before);
+        assert_eq!(qt.node_aggregates.len(), slots);
+        assert_eq!(qt.node_counts.len(), slots);
+        assert_eq!(qt.aggregate_in(rect), Sum(25));
+        assert_eq!(qt.count_in(Rect::new(0, 64, 30, 64)), 3);
+        assert_eq!((qt.maintain_cursor, qt.maintain_changed, qt.maintain_idle), (cursor, changed, idle));
+
+        // the aggregates are still kept after rollback
+        qt.remove(ids[7]);
+        qt.insert(10, Rect::new(1, 1, 1, 1));
+        assert_eq!(qt.aggregate_in(rect), Sum(28));
+    }
+
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Sum(i32);
+
+    impl Aggregate<i32> for Sum {
+        fn identity() -> Self {
+            Sum(0)
+        }
+
+        fn combine(&self, other: &Self) -> Self {
+            Sum(self.0 + other.0)
+        }
+
+        fn from_element(element: &i32) -> Self {
+            Sum(*element)
+        }
+    }
+}